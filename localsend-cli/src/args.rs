@@ -1,9 +1,10 @@
-use std::{net::Ipv4Addr, path::PathBuf};
+use std::{path::PathBuf, str::FromStr};
 
 use argh::FromArgs;
 use derive_getters::Getters;
+use localsend_lib_types::messages::common_fields::Version;
 
-use crate::utils::dbgr;
+use crate::{scan::Ipv4Subnet, utils::dbgr};
 /// LocalSend cli
 #[derive(FromArgs, PartialEq, Debug)]
 pub struct Args {
@@ -16,6 +17,11 @@ pub struct Args {
 pub enum SubCommand {
     Discover(DiscoverArgs),
     Download(DownloadArgs),
+    Receive(ReceiveArgs),
+    Send(SendArgs),
+    Share(ShareArgs),
+    Daemon(DaemonArgs),
+    Peers(PeersArgs),
 }
 /// discover surrounding localsend devices
 #[derive(FromArgs, PartialEq, Debug, Getters)]
@@ -31,26 +37,265 @@ pub struct DiscoverArgs {
     /// in seconds
     #[argh(option, default = "2")]
     announce_interval: u64,
+    /// also discover over ipv6 multicast (ff02::167), for ipv6 only networks
+    #[argh(switch)]
+    ipv6: bool,
+    /// network interface (name or ip) to discover over, may be repeated.
+    /// All interfaces if none given
+    #[argh(option)]
+    interface: Vec<String>,
+    /// network interface (name or ip) not to discover over, may be repeated
+    #[argh(option)]
+    exclude_interface: Vec<String>,
+    /// also sweep local subnets with register requests, for networks dropping multicast
+    #[argh(switch)]
+    scan: bool,
+    /// subnet to scan instead of local ones (ex 192.168.1.0/24), may be repeated.
+    /// Implies --scan
+    #[argh(option)]
+    subnet: Vec<Ipv4Subnet>,
+    /// how to print peers: text (default), jsonl (a line per peer, as discovered)
+    /// or json (an array of all peers, at timeout)
+    #[argh(option, default = "OutputFormat::Text")]
+    format: OutputFormat,
+}
+
+/// Output format of commands listing things, ex peers
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum OutputFormat {
+    /// Human readable
+    Text,
+    /// Json array, once complete
+    Json,
+    /// Json object per line, as they come
+    Jsonl,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            _ => Err(format!(
+                "unknown format {format}, expected text, json or jsonl"
+            )),
+        }
+    }
 }
 
 /// download files from some localsend device
 #[derive(FromArgs, PartialEq, Debug, Getters)]
 #[argh(subcommand, name = "download")]
 pub struct DownloadArgs {
-    /// ip address of sender
+    /// ip address, alias or fingerprint (prefix) of sender
     #[argh(positional)]
-    sender: Ipv4Addr,
-    /// specify port, if not using default (53317)
+    sender: String,
+    /// specify port of sender given by ip address, if not using default (53317)
     #[argh(option, default = "53317")]
     port: u16,
     /// security pin if set
     #[argh(option)]
     pin: Option<String>,
+    /// connect over plain http, for senders (given by ip) with encryption turned off
+    #[argh(switch)]
+    http: bool,
+    /// protocol version of sender given by ip address, if not the latest (2.1)
+    #[argh(option, default = "Version::default()")]
+    protocol_version: Version,
     /// where to download the files
     #[argh(positional, default = "get_current_dir()")]
     destination: PathBuf,
 }
 
+/// receive files sent (pushed) by other localsend devices
+#[derive(FromArgs, PartialEq, Debug, Getters)]
+#[argh(subcommand, name = "receive")]
+pub struct ReceiveArgs {
+    /// specify port, if not using default (53317)
+    #[argh(option, default = "53317")]
+    port: u16,
+    /// serve plain http instead of https
+    #[argh(switch)]
+    http: bool,
+    /// ask before accepting incoming files, which may then be picked one by one
+    #[argh(switch)]
+    confirm: bool,
+    /// require senders to provide this pin
+    #[argh(option)]
+    pin: Option<String>,
+    /// require senders to provide a random pin, printed at startup
+    #[argh(switch)]
+    random_pin: bool,
+    /// where to save the received files
+    #[argh(positional, default = "get_current_dir()")]
+    destination: PathBuf,
+}
+
+/// send (push) files to some localsend device
+#[derive(FromArgs, PartialEq, Debug, Getters)]
+#[argh(subcommand, name = "send")]
+pub struct SendArgs {
+    /// ip address, alias or fingerprint (prefix) of receiver
+    #[argh(positional)]
+    receiver: String,
+    /// files or directories to send
+    #[argh(positional)]
+    paths: Vec<PathBuf>,
+    /// specify port of receiver given by ip address, if not using default (53317)
+    #[argh(option, default = "53317")]
+    port: u16,
+    /// security pin if set
+    #[argh(option)]
+    pin: Option<String>,
+    /// connect over plain http, for receivers (given by ip) with encryption turned off
+    #[argh(switch)]
+    http: bool,
+    /// protocol version of receiver given by ip address, ex 1.0 for older apps, if not the latest (2.1)
+    #[argh(option, default = "Version::default()")]
+    protocol_version: Version,
+}
+
+/// share files, for other localsend devices to download
+#[derive(FromArgs, PartialEq, Debug, Getters)]
+#[argh(subcommand, name = "share")]
+pub struct ShareArgs {
+    /// files or directories to share
+    #[argh(positional)]
+    paths: Vec<PathBuf>,
+    /// specify port, if not using default (53317)
+    #[argh(option, default = "53317")]
+    port: u16,
+    /// require downloaders to provide this pin
+    #[argh(option)]
+    pin: Option<String>,
+    /// require downloaders to provide a random pin, printed at startup
+    #[argh(switch)]
+    random_pin: bool,
+    /// serve plain http instead of https
+    #[argh(switch)]
+    http: bool,
+    /// how long to wait before repeating announcement, in seconds
+    #[argh(option, default = "2")]
+    announce_interval: u64,
+    /// also announce over ipv6 multicast (ff02::167), for ipv6 only networks
+    #[argh(switch)]
+    ipv6: bool,
+    /// network interface (name or ip) to announce over, may be repeated.
+    /// All interfaces if none given
+    #[argh(option)]
+    interface: Vec<String>,
+    /// network interface (name or ip) not to announce over, may be repeated
+    #[argh(option)]
+    exclude_interface: Vec<String>,
+}
+
+/// stay available to nearby devices: announce ourselves, answer discovery,
+/// remember peers and receive files, until interrupted
+#[derive(FromArgs, PartialEq, Debug, Getters)]
+#[argh(subcommand, name = "daemon")]
+pub struct DaemonArgs {
+    /// specify port, if not using default (53317)
+    #[argh(option, default = "53317")]
+    port: u16,
+    /// serve plain http instead of https
+    #[argh(switch)]
+    http: bool,
+    /// how long to wait before repeating announcement, in seconds
+    #[argh(option, default = "30")]
+    announce_interval: u64,
+    /// also use ipv6 multicast (ff02::167), for ipv6 only networks
+    #[argh(switch)]
+    ipv6: bool,
+    /// network interface (name or ip) to discover over, may be repeated.
+    /// All interfaces if none given
+    #[argh(option)]
+    interface: Vec<String>,
+    /// network interface (name or ip) not to discover over, may be repeated
+    #[argh(option)]
+    exclude_interface: Vec<String>,
+    /// hold incoming sessions until accepted or rejected through the control api
+    #[argh(switch)]
+    confirm: bool,
+    /// require senders to provide this pin
+    #[argh(option)]
+    pin: Option<String>,
+    /// require senders to provide a random pin, printed at startup
+    #[argh(switch)]
+    random_pin: bool,
+    /// where to save the received files
+    #[argh(positional, default = "get_current_dir()")]
+    destination: PathBuf,
+}
+
+/// manage peers seen before
+#[derive(FromArgs, PartialEq, Debug, Getters)]
+#[argh(subcommand, name = "peers")]
+pub struct PeersArgs {
+    #[argh(subcommand)]
+    command: PeersCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+pub enum PeersCommand {
+    List(PeersListArgs),
+    Rename(PeersRenameArgs),
+    Forget(PeersForgetArgs),
+    Trust(PeersTrustArgs),
+    Untrust(PeersUntrustArgs),
+}
+
+/// list peers seen before, most recently seen first
+#[derive(FromArgs, PartialEq, Debug, Getters)]
+#[argh(subcommand, name = "list")]
+pub struct PeersListArgs {
+    /// how to print peers: text (default), json or jsonl
+    #[argh(option, default = "OutputFormat::Text")]
+    format: OutputFormat,
+}
+
+/// give a peer a nickname of our own, usable wherever its alias is
+#[derive(FromArgs, PartialEq, Debug, Getters)]
+#[argh(subcommand, name = "rename")]
+pub struct PeersRenameArgs {
+    /// alias, nickname or fingerprint (prefix) of peer
+    #[argh(positional)]
+    peer: String,
+    /// nickname to give, none to clear it
+    #[argh(positional)]
+    nickname: Option<String>,
+}
+
+/// forget a peer
+#[derive(FromArgs, PartialEq, Debug, Getters)]
+#[argh(subcommand, name = "forget")]
+pub struct PeersForgetArgs {
+    /// alias, nickname or fingerprint (prefix) of peer
+    #[argh(positional)]
+    peer: String,
+}
+
+/// mark a peer trusted. Trusted peers are remembered until forgotten
+#[derive(FromArgs, PartialEq, Debug, Getters)]
+#[argh(subcommand, name = "trust")]
+pub struct PeersTrustArgs {
+    /// alias, nickname or fingerprint (prefix) of peer
+    #[argh(positional)]
+    peer: String,
+}
+
+/// unmark a trusted peer
+#[derive(FromArgs, PartialEq, Debug, Getters)]
+#[argh(subcommand, name = "untrust")]
+pub struct PeersUntrustArgs {
+    /// alias, nickname or fingerprint (prefix) of peer
+    #[argh(positional)]
+    peer: String,
+}
+
 fn get_current_dir() -> PathBuf {
     std::env::current_dir().inspect_err(dbgr).unwrap()
 }
@@ -0,0 +1,66 @@
+//! User config, `config.toml` in the config dir. Written by hand, so it is optional
+//! and unknown keys are refused rather than silently ignored.
+//!
+//! ```toml
+//! pin = "123456"
+//!
+//! # Accept anything from trusted peers, up to 1GB
+//! [[policy]]
+//! action = "accept"
+//! trusted = true
+//! max_total_size = 1_000_000_000
+//!
+//! # Accept a few photos from anyone
+//! [[policy]]
+//! action = "accept"
+//! max_files = 20
+//! file_types = ["image/*"]
+//!
+//! [[policy]]
+//! action = "reject"
+//! ```
+use std::{io::Error as IoError, path::PathBuf};
+
+use directories::ProjectDirs;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::policy::Policy;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Pin our servers require, unless given another by flag
+    pub pin: Option<String>,
+    /// Rules deciding on incoming sessions, first match wins (see [crate::policy])
+    #[serde(default)]
+    pub policy: Vec<Policy>,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Could not read config {path:?}: {source}")]
+    Read { path: PathBuf, source: IoError },
+    #[error("Invalid config {path:?}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+}
+
+pub(crate) fn config_path() -> Option<PathBuf> {
+    let project_dirs = ProjectDirs::from("", "Nainapps", "localsend-rs")?;
+    Some(project_dirs.config_dir().join("config.toml"))
+}
+
+/// Config from the config file, defaults if there is none
+pub(crate) fn load_config() -> Result<Config, ConfigError> {
+    let Some(path) = config_path().filter(|path| path.exists()) else {
+        return Ok(Config::default());
+    };
+    let config_string = match std::fs::read_to_string(&path) {
+        Ok(config_string) => config_string,
+        Err(source) => return Err(ConfigError::Read { path, source }),
+    };
+    toml::from_str(&config_string).map_err(|source| ConfigError::Parse { path, source })
+}
@@ -1,9 +1,10 @@
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 pub const MULTICAST_IP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 167);
+/// Link-local counterpart of the ipv4 group, for ipv6 only networks. Not (yet) used by stock LocalSend.
+pub const MULTICAST_IPV6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x167);
 pub const LOCALSEND_PORT: u16 = 53317;
-
-// pub const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+/// Introducing ourselves (user agent) as the cli, not the client library
 pub const FOX_USER_AGENT: &str = concat!(
     "Mozilla/5.0 (X11; Linux;) Gecko/20100101 ",
     env!("CARGO_PKG_NAME"),
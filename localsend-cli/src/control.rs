@@ -0,0 +1,700 @@
+//! Control api of a running daemon, over a unix socket in the runtime dir.
+//! Line delimited json: every request is a json object on a line, answered by a reply line.
+//! After replying, `send` follows the transfer with its events until it ends,
+//! and `subscribe` follows all events until the connection closes.
+//!
+//! ```text
+//! > {"command":"list_sessions"}
+//! < {"reply":"sessions","incoming":[{"session_id":"3f2…","pending":true,…}],"outgoing":[]}
+//! > {"command":"accept","session_id":"3f2…"}
+//! < {"reply":"done"}
+//! ```
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    net::IpAddr,
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use directories::ProjectDirs;
+use localsend_client::LocalSendClient;
+use localsend_lib_types::messages::common_fields::{FileId, Protocol, SessionId, Version};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader as AsyncBufReader},
+    net::UnixListener,
+    sync::broadcast::{self, error::RecvError},
+    task::AbortHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    constants::LOCALSEND_PORT,
+    discover::{matching_peer, Discovery, PeerAddress, ResolvePeerError},
+    events::{Event, Events},
+    peers::PeerUpdate,
+    receive::Receiver,
+    send::{accepted_uploads, prepare_upload_request, upload_files},
+    session::{Decision, IncomingSession},
+    state::KnownPeer,
+    utils::{collect_files, retry_too_many_requests},
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Peers known, including those just seen by the daemon
+    ListPeers,
+    /// Send files (absolute paths) to peer given by ip address, alias, nickname
+    /// or fingerprint (prefix)
+    Send {
+        peer: String,
+        paths: Vec<PathBuf>,
+        #[serde(default)]
+        pin: Option<String>,
+        /// Port of peer given by ip address, if not the default (53317)
+        #[serde(default)]
+        port: Option<u16>,
+        /// Connect to peer given by ip address over plain http
+        #[serde(default)]
+        http: bool,
+        /// Protocol version of peer given by ip address, if not the latest (2.1)
+        #[serde(default)]
+        protocol_version: Option<Version>,
+    },
+    /// Rename, forget or (un)trust known peer given by alias, nickname or fingerprint (prefix)
+    UpdatePeer {
+        peer: String,
+        update: PeerUpdate,
+    },
+    /// Incoming sessions and outgoing transfers
+    ListSessions,
+    /// Accept pending incoming session, only given files if any
+    Accept {
+        session_id: SessionId,
+        #[serde(default)]
+        files: Option<Vec<FileId>>,
+    },
+    Reject {
+        session_id: SessionId,
+    },
+    /// Cancel incoming session (by session id) or outgoing transfer (by transfer id)
+    Cancel {
+        id: String,
+    },
+    Subscribe,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+pub enum Reply {
+    Peers {
+        peers: Vec<KnownPeer>,
+    },
+    Sessions {
+        incoming: Vec<IncomingSession>,
+        outgoing: Vec<OutgoingTransfer>,
+    },
+    /// Known peer updated, as described
+    PeerUpdated {
+        outcome: String,
+    },
+    /// Transfer started, its events follow
+    Sending {
+        transfer_id: String,
+    },
+    /// Events follow
+    Subscribed,
+    Done,
+    Error {
+        message: String,
+    },
+}
+
+/// Outgoing transfer, as reported to the control api
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingTransfer {
+    pub transfer_id: String,
+    pub peer: String,
+    pub paths: Vec<PathBuf>,
+    /// Set once receiver accepted
+    pub session_id: Option<SessionId>,
+}
+
+/// Where the control socket is: in the runtime dir where there is one, else the state dir
+pub fn control_socket_path() -> Option<PathBuf> {
+    let project_dirs = ProjectDirs::from("", "Nainapps", "localsend-rs")?;
+    let dir = project_dirs
+        .runtime_dir()
+        .unwrap_or(project_dirs.data_local_dir());
+    Some(dir.join("control.sock"))
+}
+
+/// Daemon side of the control api
+pub struct Controller {
+    discovery: Arc<Discovery>,
+    receiver: Arc<Receiver>,
+    events: Events,
+    /// What we tell receivers to reach us by
+    port: u16,
+    protocol: Protocol,
+    outgoing: Mutex<HashMap<String, Outgoing>>,
+}
+
+struct Outgoing {
+    transfer: OutgoingTransfer,
+    task: AbortHandle,
+    client: LocalSendClient,
+}
+
+impl Controller {
+    pub fn new(
+        discovery: Arc<Discovery>,
+        receiver: Arc<Receiver>,
+        events: Events,
+        port: u16,
+        protocol: Protocol,
+    ) -> Self {
+        Controller {
+            discovery,
+            receiver,
+            events,
+            port,
+            protocol,
+            outgoing: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Answer requests read from reader, until it closes or shutdown
+    async fn serve_connection<R, W>(
+        self: Arc<Self>,
+        reader: R,
+        mut writer: W,
+        shutdown: CancellationToken,
+    ) where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut lines = AsyncBufReader::new(reader).lines();
+        loop {
+            let line = tokio::select! {
+                line = lines.next_line() => line,
+                () = shutdown.cancelled() => return,
+            };
+            let Ok(Some(line)) = line else {
+                return;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let request = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => request,
+                Err(error) => {
+                    let message = format!("Invalid request: {error}");
+                    if write_line(&mut writer, &Reply::Error { message })
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let served = match request {
+                Request::ListPeers => write_line(&mut writer, &self.list_peers()).await,
+                Request::UpdatePeer { peer, update } => {
+                    let reply = self.update_peer(&peer, &update);
+                    write_line(&mut writer, &reply).await
+                }
+                Request::ListSessions => write_line(&mut writer, &self.list_sessions()).await,
+                Request::Accept { session_id, files } => {
+                    let reply = self.decide(&session_id, Decision::Accept(files));
+                    write_line(&mut writer, &reply).await
+                }
+                Request::Reject { session_id } => {
+                    let reply = self.decide(&session_id, Decision::Reject);
+                    write_line(&mut writer, &reply).await
+                }
+                Request::Cancel { id } => write_line(&mut writer, &self.cancel(&id)).await,
+                Request::Subscribe => {
+                    let events = self.events.subscribe();
+                    if write_line(&mut writer, &Reply::Subscribed).await.is_ok() {
+                        let _ = follow_events(events, &mut writer, None, &shutdown).await;
+                    }
+                    return;
+                }
+                Request::Send {
+                    peer,
+                    paths,
+                    pin,
+                    port,
+                    http,
+                    protocol_version,
+                } => {
+                    // Subscribe first, not to miss early events
+                    let events = self.events.subscribe();
+                    let protocol = if http {
+                        Protocol::Http
+                    } else {
+                        Protocol::Https
+                    };
+                    let port = port.unwrap_or(LOCALSEND_PORT);
+                    let version = protocol_version.unwrap_or_default();
+                    match self.start_send(peer, paths, pin, port, protocol, version) {
+                        Ok(transfer_id) => {
+                            let reply = Reply::Sending {
+                                transfer_id: transfer_id.clone(),
+                            };
+                            match write_line(&mut writer, &reply).await {
+                                Ok(()) => {
+                                    follow_events(
+                                        events,
+                                        &mut writer,
+                                        Some(&transfer_id),
+                                        &shutdown,
+                                    )
+                                    .await
+                                }
+                                Err(error) => Err(error),
+                            }
+                        }
+                        Err(message) => write_line(&mut writer, &Reply::Error { message }).await,
+                    }
+                }
+            };
+            if served.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn list_peers(&self) -> Reply {
+        Reply::Peers {
+            peers: self
+                .discovery
+                .update_known_peers(|known_peers| known_peers.clone()),
+        }
+    }
+
+    fn update_peer(&self, peer: &str, update: &PeerUpdate) -> Reply {
+        let outcome = self
+            .discovery
+            .update_known_peers(|known_peers| update.apply(known_peers, peer));
+        match outcome {
+            Ok(outcome) => Reply::PeerUpdated { outcome },
+            Err(message) => Reply::Error { message },
+        }
+    }
+
+    fn list_sessions(&self) -> Reply {
+        let outgoing = self.outgoing.lock().expect("outgoing lock poisoned");
+        Reply::Sessions {
+            incoming: self.receiver.sessions(),
+            outgoing: outgoing
+                .values()
+                .map(|outgoing| outgoing.transfer.clone())
+                .collect(),
+        }
+    }
+
+    fn decide(&self, session_id: &SessionId, decision: Decision) -> Reply {
+        if self.receiver.decide(session_id, decision) {
+            Reply::Done
+        } else {
+            Reply::Error {
+                message: format!("No session {session_id} pending"),
+            }
+        }
+    }
+
+    fn cancel(&self, id: &str) -> Reply {
+        if self.receiver.cancel_session(Some(&id.into())) || self.cancel_transfer(id) {
+            Reply::Done
+        } else {
+            Reply::Error {
+                message: format!("No session or transfer {id}"),
+            }
+        }
+    }
+
+    /// Stop outgoing transfer, telling the receiver if it accepted already
+    fn cancel_transfer(&self, transfer_id: &str) -> bool {
+        let mut outgoing = self.outgoing.lock().expect("outgoing lock poisoned");
+        let Some(Outgoing {
+            transfer,
+            task,
+            client,
+        }) = outgoing.remove(transfer_id)
+        else {
+            return false;
+        };
+        task.abort();
+        if let Some(session_id) = transfer.session_id {
+            tokio::spawn(async move {
+                let _ = client.cancel(&session_id).await;
+            });
+        }
+        self.events.emit(Event::SendCancelled {
+            transfer_id: transfer_id.to_string(),
+        });
+        true
+    }
+
+    /// Start sending files to peer, returning transfer id.
+    /// Fails if peer can't be resolved.
+    fn start_send(
+        self: &Arc<Self>,
+        peer: String,
+        paths: Vec<PathBuf>,
+        pin: Option<String>,
+        port: u16,
+        protocol: Protocol,
+        version: Version,
+    ) -> Result<String, String> {
+        let peer_address = self
+            .resolve_peer(&peer, port, protocol, version)
+            .map_err(|error| error.to_string())?;
+        let client = peer_address.client().map_err(|error| error.to_string())?;
+        let transfer_id = uuid::Uuid::new_v4().to_string();
+        let transfer = OutgoingTransfer {
+            transfer_id: transfer_id.clone(),
+            peer: peer.clone(),
+            paths: paths.clone(),
+            session_id: None,
+        };
+        // Held while spawning, so the transfer is listed before it can end
+        let mut outgoing = self.outgoing.lock().expect("outgoing lock poisoned");
+        let task = tokio::spawn(self.clone().run_send(
+            transfer_id.clone(),
+            peer,
+            peer_address,
+            client.clone(),
+            paths,
+            pin,
+        ));
+        outgoing.insert(
+            transfer_id.clone(),
+            Outgoing {
+                transfer,
+                task: task.abort_handle(),
+                client,
+            },
+        );
+        Ok(transfer_id)
+    }
+
+    /// Peer by ip address, or among known peers (including those just seen).
+    /// No discovery pass is run, the daemon is discovering already.
+    fn resolve_peer(
+        &self,
+        peer: &str,
+        port: u16,
+        protocol: Protocol,
+        version: Version,
+    ) -> Result<PeerAddress, ResolvePeerError> {
+        if let Ok(address) = peer.parse::<IpAddr>() {
+            return Ok(PeerAddress {
+                address,
+                port,
+                protocol,
+                fingerprint: None,
+                version,
+                scope_id: None,
+            });
+        }
+        let known_peers = self
+            .discovery
+            .update_known_peers(|known_peers| known_peers.clone());
+        match matching_peer(peer, known_peers.iter())? {
+            Some(known_peer) => Ok((&known_peer.peer_info).into()),
+            None => Err(ResolvePeerError::NotFound(peer.to_string())),
+        }
+    }
+
+    async fn run_send(
+        self: Arc<Self>,
+        transfer_id: String,
+        peer: String,
+        peer_address: PeerAddress,
+        client: LocalSendClient,
+        paths: Vec<PathBuf>,
+        pin: Option<String>,
+    ) {
+        self.events.emit(Event::SendStarted {
+            transfer_id: transfer_id.clone(),
+            peer,
+        });
+        let sent = self
+            .send_files(&transfer_id, &peer_address, &client, paths, pin)
+            .await;
+        let event = match sent {
+            Ok(()) => Event::SendFinished {
+                transfer_id: transfer_id.clone(),
+            },
+            Err(reason) => Event::SendFailed {
+                transfer_id: transfer_id.clone(),
+                reason,
+            },
+        };
+        self.outgoing
+            .lock()
+            .expect("outgoing lock poisoned")
+            .remove(&transfer_id);
+        self.events.emit(event);
+    }
+
+    async fn send_files(
+        &self,
+        transfer_id: &str,
+        peer_address: &PeerAddress,
+        client: &LocalSendClient,
+        paths: Vec<PathBuf>,
+        pin: Option<String>,
+    ) -> Result<(), String> {
+        peer_address
+            .identify(client, &self.discovery.legacy_register())
+            .await
+            .map_err(|error| format!("Could not identify receiver: {error}"))?;
+        // Files are hashed while collected
+        let local_files = tokio::task::spawn_blocking(move || collect_files(&paths))
+            .await
+            .expect("collecting files shouldn't panic")
+            .map_err(|error| format!("Could not read files to send: {error}"))?;
+        if local_files.is_empty() {
+            return Err("Nothing to send".to_string());
+        }
+        let request = prepare_upload_request(&local_files, Some((self.port, self.protocol)));
+        let response = retry_too_many_requests(|| client.prepare_upload(&request, pin.as_deref()))
+            .await
+            .map_err(|error| format!("Receiver did not accept: {error}"))?;
+        let session_id = response.session_id().clone();
+        if let Some(outgoing) = self
+            .outgoing
+            .lock()
+            .expect("outgoing lock poisoned")
+            .get_mut(transfer_id)
+        {
+            outgoing.transfer.session_id = Some(session_id.clone());
+        }
+        let uploads = accepted_uploads(&response, &local_files);
+        self.events.emit(Event::SendAccepted {
+            transfer_id: transfer_id.to_string(),
+            session_id: session_id.clone(),
+            files: uploads.len(),
+        });
+        for (path, result) in upload_files(client, &session_id, uploads).await {
+            let file_name = path.display().to_string();
+            self.events.emit(match result {
+                Ok(()) => Event::FileSent {
+                    transfer_id: transfer_id.to_string(),
+                    file_name,
+                },
+                Err(reason) => Event::FileSendFailed {
+                    transfer_id: transfer_id.to_string(),
+                    file_name,
+                    reason,
+                },
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Serve the control api at socket path until shutdown, removing the socket once done.
+/// A socket left behind (ex by a daemon killed) is replaced.
+pub async fn serve_control(
+    controller: Arc<Controller>,
+    socket_path: PathBuf,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    // Only we may drive the daemon: the socket is out of others' reach from the moment it is bound.
+    // Fails if the directory is someone else's.
+    if let Some(socket_dir) = socket_path.parent() {
+        std::fs::create_dir_all(socket_dir)?;
+        std::fs::set_permissions(socket_dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    let _ = std::fs::remove_file(&socket_path);
+    let listener = UnixListener::bind(&socket_path)?;
+    std::fs::set_permissions(&socket_path, std::fs::Permissions::from_mode(0o600))?;
+    let mut connections = tokio::task::JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _address)) => {
+                    let (reader, writer) = stream.into_split();
+                    connections.spawn(
+                        controller.clone().serve_connection(reader, writer, shutdown.clone()),
+                    );
+                }
+                Err(error) => eprintln!("Could not accept control connection: {error}"),
+            },
+            () = shutdown.cancelled() => break,
+        }
+    }
+    connections.join_all().await;
+    let _ = std::fs::remove_file(&socket_path);
+    Ok(())
+}
+
+/// Write events (only those of given transfer, if any) as lines,
+/// until the transfer ends, the connection closes, or shutdown
+async fn follow_events(
+    mut events: broadcast::Receiver<Event>,
+    writer: &mut (impl AsyncWrite + Unpin),
+    transfer_id: Option<&str>,
+    shutdown: &CancellationToken,
+) -> io::Result<()> {
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            () = shutdown.cancelled() => return Ok(()),
+        };
+        let event = match event {
+            Ok(event) => event,
+            // Slow reader missed some, carry on with the rest
+            Err(RecvError::Lagged(_missed)) => continue,
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        match transfer_id {
+            Some(transfer_id) if event.transfer_id() != Some(transfer_id) => continue,
+            _ => {}
+        }
+        write_line(writer, &event).await?;
+        if transfer_id.is_some() && event.ends_transfer() {
+            return Ok(());
+        }
+    }
+}
+
+async fn write_line(
+    writer: &mut (impl AsyncWrite + Unpin),
+    value: &impl Serialize,
+) -> io::Result<()> {
+    let mut line = serde_json::to_string(value)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await
+}
+
+/// Connection to a running daemon's control api
+pub struct ControlClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl ControlClient {
+    /// Connect to the daemon, if one is running
+    pub fn connect() -> Option<Self> {
+        let stream = UnixStream::connect(control_socket_path()?).ok()?;
+        Some(ControlClient {
+            reader: BufReader::new(stream.try_clone().ok()?),
+            writer: stream,
+        })
+    }
+
+    pub fn request(&mut self, request: &Request) -> io::Result<()> {
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())
+    }
+
+    /// Next reply, or event
+    pub fn read<T: DeserializeOwned>(&mut self) -> io::Result<T> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "daemon closed the connection",
+            ));
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use localsend_lib_types::messages::{
+        common_fields::{DeviceInfo, DeviceType, Fingerprint, Protocol},
+        discover::MulticastCommon,
+    };
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio_util::sync::CancellationToken;
+
+    use super::{Controller, Reply};
+    use crate::{args::OutputFormat, discover::Discovery, events::Event, receive::Receiver};
+
+    #[tokio::test]
+    async fn requests_answered_line_by_line() {
+        let device_info = DeviceInfo::new(
+            "Nice Orange".to_string().into(),
+            None,
+            DeviceType::Headless,
+            Fingerprint::new("ours".to_string()),
+        );
+        let announcement = MulticastCommon::new(device_info, 53317.into(), Protocol::Https, None);
+        let events = crate::events::Events::new();
+        let controller = Arc::new(Controller::new(
+            Arc::new(Discovery::new(announcement, OutputFormat::Json)),
+            Arc::new(Receiver::new(std::env::temp_dir()).with_events(events.clone())),
+            events.clone(),
+            53317,
+            Protocol::Https,
+        ));
+        let (client, server) = tokio::io::duplex(4096);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let shutdown = CancellationToken::new();
+        let served = tokio::spawn(controller.serve_connection(
+            server_reader,
+            server_writer,
+            shutdown.clone(),
+        ));
+        let (client_reader, mut client_writer) = tokio::io::split(client);
+        let mut replies = BufReader::new(client_reader).lines();
+        let mut ask = async |request: &str| -> String {
+            client_writer
+                .write_all(format!("{request}\n").as_bytes())
+                .await
+                .unwrap();
+            replies.next_line().await.unwrap().unwrap()
+        };
+
+        let reply = ask("not json").await;
+        assert!(matches!(
+            serde_json::from_str(&reply).unwrap(),
+            Reply::Error { .. }
+        ));
+        let reply = ask(r#"{"command":"list_sessions"}"#).await;
+        let Reply::Sessions { incoming, outgoing } = serde_json::from_str(&reply).unwrap() else {
+            panic!("expected sessions, got {reply}");
+        };
+        assert!(incoming.is_empty() && outgoing.is_empty());
+        let reply = ask(r#"{"command":"accept","session_id":"nope"}"#).await;
+        assert!(matches!(
+            serde_json::from_str(&reply).unwrap(),
+            Reply::Error { .. }
+        ));
+        let reply = ask(r#"{"command":"cancel","id":"nope"}"#).await;
+        assert!(matches!(
+            serde_json::from_str(&reply).unwrap(),
+            Reply::Error { .. }
+        ));
+        let reply = ask(r#"{"command":"subscribe"}"#).await;
+        assert!(matches!(
+            serde_json::from_str(&reply).unwrap(),
+            Reply::Subscribed
+        ));
+        events.emit(Event::PeerLeft {
+            fingerprint: Fingerprint::new("theirs".to_string()),
+        });
+        let event = replies.next_line().await.unwrap().unwrap();
+        assert!(matches!(
+            serde_json::from_str(&event).unwrap(),
+            Event::PeerLeft { .. }
+        ));
+        shutdown.cancel();
+        served.await.unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+//! Always-on presence: discovery, announcements and the receive server together,
+//! running until interrupted.
+use std::{sync::Arc, thread, time::Duration};
+
+use localsend_lib_types::messages::{common_fields::Protocol, discover::MulticastCommon};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    args::{DaemonArgs, OutputFormat},
+    config::load_config,
+    discover::{listen_broadcasts, register_routes, Discovery},
+    events::Events,
+    multicast::{join_multicast_groups, MulticastScope},
+    pin::choose_pin,
+    receive::{upload_routes, Approval, Receiver},
+    server::serve,
+    state::load_state,
+    utils::dbgr,
+};
+
+/// Announce ourselves, answer announcements and register requests, remember peers seen,
+/// and receive files pushed to us, until SIGINT or SIGTERM (or the server failing).
+/// Then stop announcing and answering announcements, multicast a goodbye,
+/// let uploads in flight finish, and save peers before exiting.
+/// The protocol has no goodbye message, so only peers running this cli act on ours,
+/// others forget us once our announcements stop.
+/// On unix, the daemon is driven through its control api (see [crate::control]).
+pub fn daemon(daemon_args: DaemonArgs) {
+    #[cfg(unix)]
+    if crate::control::ControlClient::connect().is_some() {
+        println!("A daemon is running already");
+        return;
+    }
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(error) => {
+            println!("{error}");
+            return;
+        }
+    };
+    let device_info = load_state().device_info;
+    let protocol = if daemon_args.http() {
+        Protocol::Http
+    } else {
+        Protocol::Https
+    };
+    let announcement = MulticastCommon::new(
+        device_info.clone(),
+        daemon_args.port().into(),
+        protocol,
+        Some(false),
+    );
+    let events = Events::new();
+    let discovery =
+        Arc::new(Discovery::new(announcement, OutputFormat::Text).with_events(events.clone()));
+    let approval = if daemon_args.confirm() {
+        Approval::Pending
+    } else {
+        Approval::Auto
+    };
+    let pin = choose_pin(
+        daemon_args.random_pin(),
+        daemon_args.pin().clone(),
+        config.pin,
+    );
+    let receiver = Arc::new(
+        Receiver::new(daemon_args.destination().to_path_buf())
+            .with_approval(approval)
+            .with_policies(config.policy)
+            .with_pin(pin)
+            .with_events(events.clone()),
+    );
+    let scope = MulticastScope {
+        ipv6: daemon_args.ipv6(),
+        interfaces: daemon_args.interface().clone(),
+        exclude_interfaces: daemon_args.exclude_interface().clone(),
+    };
+    let rt = tokio::runtime::Runtime::new().inspect_err(dbgr).unwrap();
+    let shutdown = CancellationToken::new();
+
+    let routes = upload_routes(receiver.clone()).merge(register_routes(discovery.clone()));
+    let mut serve_handle = rt.spawn(serve(
+        routes,
+        daemon_args.port(),
+        protocol,
+        shutdown.clone(),
+    ));
+    let keep_peers_handle = rt.spawn(keep_peers(discovery.clone(), shutdown.clone()));
+    #[cfg(unix)]
+    let control_handle = crate::control::control_socket_path().map(|socket_path| {
+        let controller = Arc::new(crate::control::Controller::new(
+            discovery.clone(),
+            receiver,
+            events,
+            daemon_args.port(),
+            protocol,
+        ));
+        println!("Control api at {socket_path:?}");
+        let shutdown = shutdown.clone();
+        rt.spawn(async move {
+            let served = crate::control::serve_control(controller, socket_path, shutdown).await;
+            if let Err(error) = served {
+                eprintln!("Could not serve control api: {error}");
+            }
+        })
+    });
+    #[cfg(not(unix))]
+    let _ = (receiver, events);
+    let announce_interval = Duration::from_secs(daemon_args.announce_interval());
+    let listen_broadcasts_handles: Vec<_> = join_multicast_groups(&scope)
+        .into_iter()
+        .map(|socket| {
+            let discovery = discovery.clone();
+            let rt_handle = rt.handle().clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                listen_broadcasts(
+                    discovery,
+                    rt_handle,
+                    socket,
+                    Some(announce_interval),
+                    shutdown,
+                )
+            })
+        })
+        .collect();
+    println!(
+        "Running as {device_info}, receiving on port {} to {:?}",
+        daemon_args.port(),
+        daemon_args.destination()
+    );
+
+    let served = rt.block_on(async {
+        tokio::select! {
+            () = shutdown_signal() => None,
+            served = &mut serve_handle => Some(served),
+        }
+    });
+    eprintln!("Shutting down");
+    shutdown.cancel();
+    let served = match served {
+        Some(served) => served,
+        None => rt.block_on(serve_handle),
+    };
+    if let Ok(Err(error)) = served {
+        eprintln!("Could not serve: {error}");
+    }
+    for listen_broadcasts_handle in listen_broadcasts_handles {
+        listen_broadcasts_handle
+            .join()
+            .expect("listener thread shouldn't panic");
+    }
+    let _ = rt.block_on(keep_peers_handle);
+    #[cfg(unix)]
+    if let Some(control_handle) = control_handle {
+        let _ = rt.block_on(control_handle);
+    }
+    eprintln!("Stopped");
+}
+
+/// Remember peers seen every PEERS_SAVE_INTERVAL, and once more on shutdown
+async fn keep_peers(discovery: Arc<Discovery>, shutdown: CancellationToken) {
+    let mut save_interval = tokio::time::interval(PEERS_SAVE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = save_interval.tick() => {}
+            () = shutdown.cancelled() => break,
+        }
+        discovery.save_peers();
+    }
+    discovery.save_peers();
+}
+
+/// Resolves on SIGINT (ctrl-c), or SIGTERM where there is one
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler install");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// How often peers seen are saved to the state dir
+const PEERS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
@@ -1,140 +1,800 @@
 use std::{
     collections::HashMap,
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, State},
+    routing::post,
+    Json, Router,
+};
 use derive_more::derive::Display;
+use localsend_client::{errors::ClientError, LocalSendClient};
 use localsend_lib_types::messages::{
-    common_fields::{DeviceInfo, Fingerprint, Port, Protocol},
-    discover::{MulticastAnnounce, MulticastCommon, MulticastMessage},
+    common_fields::{DeviceInfo, Fingerprint, Port, Protocol, Version},
+    discover::{
+        LegacyRegister, LegacyRegisterResponse, MulticastAnnounce, MulticastCommon,
+        MulticastMessage, MulticastResponse,
+    },
+    v1,
 };
-use multicast_socket::{Interface, MulticastSocket};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::runtime::Handle;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    args::DiscoverArgs,
-    constants::{LOCALSEND_PORT, MULTICAST_IP},
-    state::load_state,
+    args::{DiscoverArgs, OutputFormat},
+    constants::{FOX_USER_AGENT, LOCALSEND_PORT, MULTICAST_IP, MULTICAST_IPV6},
+    events::{Event, Events},
+    multicast::{interface_of, join_multicast_groups, MulticastChannel, MulticastScope},
+    scan::{local_subnets, scan_subnets, Ipv4Subnet},
+    server::{serve, ApiError},
+    state::{
+        load_known_peers, load_state, remember_peer, remember_peers, save_known_peers, unix_now,
+        KnownPeer,
+    },
+    utils::{dbgr, link_local_scope, peer_url},
 };
 
 /// Discover nearby localsend devices/peers
-/// Currently support only Multicast Announce and Multicast Response
+/// Currently support Multicast Announce and Multicast Response, peers registering back (http),
+/// and scanning subnets with register requests
 pub fn discover(discover_args: DiscoverArgs) {
-    let state = load_state();
-    let device_info = state.device_info;
-    let announce_interval = discover_args.announce_interval();
-    if !discover_args.silent() {
-        let _announce_broadcast_handle = thread::spawn({
-            // Capture a clone https://stackoverflow.com/a/74817347
-            let device_info = device_info.clone();
-            move || announce_broadcast(device_info.clone(), announce_interval)
-        });
+    let announce_interval =
+        (!discover_args.silent()).then(|| Duration::from_secs(discover_args.announce_interval()));
+    let scope = MulticastScope {
+        ipv6: discover_args.ipv6(),
+        interfaces: discover_args.interface().clone(),
+        exclude_interfaces: discover_args.exclude_interface().clone(),
+    };
+    let scan = if !discover_args.subnet().is_empty() {
+        discover_args.subnet().clone()
+    } else if discover_args.scan() {
+        local_subnets(&scope)
+    } else {
+        Vec::new()
     };
-    {
-        // Similar to above. Shadow Clone in scope then capture.
-        let device_info = device_info.clone();
-        let _listen_broadcasts_handle =
-            thread::spawn(move || listen_broadcasts(device_info.clone()));
+    let peers = discover_peers(
+        *discover_args.format(),
+        announce_interval,
+        Duration::from_secs(discover_args.timeout()),
+        &scope,
+        scan,
+    );
+    if *discover_args.format() == OutputFormat::Json {
+        let mut peers: Vec<&PeerInfo> = peers.values().collect();
+        peers.sort_by_key(|peer_info| peer_info.device_info.alias().to_string());
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&peers).expect("fix this serialization")
+        );
     }
+}
 
-    thread::sleep(Duration::from_secs(discover_args.timeout()));
+/// Run a discovery pass for timeout, returning the peers found.
+/// If announce interval is given, we announce ourselves and answer others' announcements,
+/// else just listen, over the multicast groups and interfaces in scope.
+/// Subnets given are scanned meanwhile, registering with each host (which reveals us too).
+/// Peers found are remembered, for resolving them later.
+fn discover_peers(
+    format: OutputFormat,
+    announce_interval: Option<Duration>,
+    timeout: Duration,
+    scope: &MulticastScope,
+    scan: Vec<Ipv4Subnet>,
+) -> PeersMap {
+    let device_info = load_state().device_info;
+    let discovery = Arc::new(Discovery::new(
+        MulticastCommon::new(
+            device_info,
+            LOCALSEND_PORT.into(),
+            Protocol::Https,
+            Some(false),
+        ),
+        format,
+    ));
+    let rt = tokio::runtime::Runtime::new().inspect_err(dbgr).unwrap();
+    let stop = CancellationToken::new();
+    rt.spawn({
+        let stop = stop.clone();
+        async move {
+            tokio::time::sleep(timeout).await;
+            stop.cancel();
+        }
+    });
+    // Peers answering our announcement register back here
+    let serve_handle = announce_interval.is_some().then(|| {
+        let routes = register_routes(discovery.clone());
+        let stop = stop.clone();
+        rt.spawn(async move {
+            if let Err(error) = serve(routes, LOCALSEND_PORT, Protocol::Https, stop).await {
+                eprintln!("Could not serve register endpoint, peers may not reply: {error}");
+            }
+        })
+    });
+    let scan_handle = (!scan.is_empty()).then(|| {
+        let discovery = discovery.clone();
+        let stop = stop.clone();
+        // Scan results past the deadline are not waited for
+        rt.spawn(async move {
+            tokio::select! {
+                () = scan_subnets(discovery, scan) => {}
+                () = stop.cancelled() => {}
+            }
+        })
+    });
+    let listen_broadcasts_handles: Vec<_> = join_multicast_groups(scope)
+        .into_iter()
+        .map(|socket| {
+            // Shadow Clone in scope then capture https://stackoverflow.com/a/74817347
+            let discovery = discovery.clone();
+            let rt_handle = rt.handle().clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                listen_broadcasts(discovery, rt_handle, socket, announce_interval, stop)
+            })
+        })
+        .collect();
+    for listen_broadcasts_handle in listen_broadcasts_handles {
+        listen_broadcasts_handle
+            .join()
+            .expect("listener thread shouldn't panic");
+    }
+    if let Some(scan_handle) = scan_handle {
+        let _ = rt.block_on(scan_handle);
+    }
+    if let Some(serve_handle) = serve_handle {
+        let _ = rt.block_on(serve_handle);
+    }
+    let peers = discovery.peers.lock().expect("peers lock poisoned").clone();
+    remember_peers(peers.values().cloned());
+    peers
 }
 
-fn listen_broadcasts(device_info: DeviceInfo) -> PeersMap {
-    println!("Listening for broadcasts!");
-    let mulicast_address = SocketAddrV4::new(MULTICAST_IP, LOCALSEND_PORT);
-    let socket = MulticastSocket::all_interfaces(mulicast_address).unwrap();
-    let mut peers: PeersMap = HashMap::new();
-    loop {
-        let Ok(udp_message) = socket.receive() else {
+/// Where to reach a peer, and what its certificate should hash to, if known
+#[derive(Debug)]
+pub struct PeerAddress {
+    pub address: IpAddr,
+    pub port: u16,
+    pub protocol: Protocol,
+    pub fingerprint: Option<Fingerprint>,
+    /// Protocol version peer speaks, deciding the routes we use
+    pub version: Version,
+    /// Scope of a link-local address
+    pub scope_id: Option<u32>,
+}
+
+impl PeerAddress {
+    /// Client for the peer, pinned to its fingerprint if known
+    pub fn client(&self) -> Result<LocalSendClient, ClientError> {
+        let base_url = peer_url(self.protocol, self.address, self.port);
+        let client = match &self.fingerprint {
+            Some(fingerprint) => LocalSendClient::pinned(base_url, fingerprint.clone()),
+            None => LocalSendClient::new(base_url),
+        };
+        let client = client?.with_user_agent(FOX_USER_AGENT)?;
+        let client = match self.scope_id {
+            Some(scope_id) => client.with_scope_id(scope_id)?,
+            None => client,
+        };
+        Ok(client.with_version(self.version))
+    }
+
+    /// Make sure client talks to the peer it claims to be, if its fingerprint is unknown (https):
+    /// registering as given, the client is pinned to the certificate the peer's fingerprint is from
+    pub async fn identify(
+        &self,
+        client: &LocalSendClient,
+        register: &LegacyRegister,
+    ) -> Result<(), ClientError> {
+        if self.fingerprint.is_none() && self.protocol == Protocol::Https {
+            client.register(register).await?;
+        }
+        Ok(())
+    }
+}
+
+impl From<&PeerInfo> for PeerAddress {
+    fn from(peer_info: &PeerInfo) -> Self {
+        // Before v2, fingerprints needn't be certificate hashes
+        let fingerprint = (peer_info.version >= Version::V2_0)
+            .then(|| peer_info.device_info.fingerprint().clone());
+        PeerAddress {
+            address: peer_info.address,
+            port: peer_info.port.dissolve(),
+            protocol: peer_info.protocol,
+            fingerprint,
+            version: peer_info.version,
+            scope_id: peer_info.scope_id,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ResolvePeerError {
+    #[error("No peer found matching {0:?}, is it nearby and running localsend?")]
+    NotFound(String),
+    #[error("No known peer matches {0:?}, see `localsend-cli peers list`")]
+    Unknown(String),
+    #[error("Several peers match {peer:?}, use a longer fingerprint prefix:\n{}", candidates.join("\n"))]
+    Ambiguous {
+        peer: String,
+        candidates: Vec<String>,
+    },
+}
+
+/// Resolve peer given by ip address, alias, nickname or fingerprint (prefix).
+/// Ip addresses are taken as is, with port, protocol and protocol version given.
+/// Otherwise known peers are searched first, then a short discovery pass is run.
+pub fn resolve_peer(
+    peer: &str,
+    port: u16,
+    protocol: Protocol,
+    version: Version,
+) -> Result<PeerAddress, ResolvePeerError> {
+    if let Ok(address) = peer.parse::<IpAddr>() {
+        return Ok(PeerAddress {
+            address,
+            port,
+            protocol,
+            fingerprint: None,
+            version,
+            scope_id: None,
+        });
+    }
+    if let Some(known_peer) = matching_peer(peer, load_known_peers().iter())? {
+        return Ok((&known_peer.peer_info).into());
+    }
+    eprintln!("Looking for {peer:?} nearby");
+    let peers = discover_peers(
+        OutputFormat::Json,
+        Some(Duration::from_secs(1)),
+        RESOLVE_TIMEOUT,
+        &MulticastScope {
+            ipv6: true,
+            ..Default::default()
+        },
+        Vec::new(),
+    );
+    // Found peers were just remembered, nicknames and all
+    let known_peers = load_known_peers();
+    let found_peers = known_peers
+        .iter()
+        .filter(|known_peer| peers.contains_key(known_peer.fingerprint()));
+    match matching_peer(peer, found_peers)? {
+        Some(known_peer) => Ok((&known_peer.peer_info).into()),
+        None => Err(ResolvePeerError::NotFound(peer.to_string())),
+    }
+}
+
+/// The one known peer with given alias or nickname (ignoring case),
+/// or fingerprint starting with given prefix
+pub(crate) fn matching_peer<'a>(
+    peer: &str,
+    known_peers: impl Iterator<Item = &'a KnownPeer>,
+) -> Result<Option<&'a KnownPeer>, ResolvePeerError> {
+    let prefix = peer.to_lowercase();
+    let matches: Vec<&KnownPeer> = known_peers
+        .filter(|known_peer| {
+            known_peer
+                .peer_info
+                .device_info
+                .alias()
+                .to_string()
+                .eq_ignore_ascii_case(peer)
+                || known_peer
+                    .nickname
+                    .as_ref()
+                    .is_some_and(|nickname| nickname.eq_ignore_ascii_case(peer))
+                || known_peer
+                    .fingerprint()
+                    .as_ref()
+                    .to_lowercase()
+                    .starts_with(&prefix)
+        })
+        .collect();
+    match matches[..] {
+        [] => Ok(None),
+        [known_peer] => Ok(Some(known_peer)),
+        _ => Err(ResolvePeerError::Ambiguous {
+            peer: peer.to_string(),
+            candidates: matches
+                .iter()
+                .map(|known_peer| {
+                    format!(
+                        "{} {}",
+                        known_peer.fingerprint(),
+                        known_peer.peer_info.device_info
+                    )
+                })
+                .collect(),
+        }),
+    }
+}
+
+/// Discovery state, shared by multicast listener and register endpoint
+pub struct Discovery {
+    /// What we announce ourselves as, and reply to peers with
+    announcement: MulticastCommon,
+    /// How new peers are reported. Json arrays are left to whoever collects the peers.
+    format: OutputFormat,
+    peers: Mutex<PeersMap>,
+    /// Peers seen since last taken, for long running discovery to remember as it goes
+    unsaved_peers: Mutex<PeersMap>,
+    events: Events,
+}
+
+impl Discovery {
+    pub fn new(announcement: MulticastCommon, format: OutputFormat) -> Self {
+        Discovery {
+            announcement,
+            format,
+            peers: Mutex::new(HashMap::new()),
+            unsaved_peers: Mutex::new(HashMap::new()),
+            events: Events::new(),
+        }
+    }
+
+    pub fn with_events(self, events: Events) -> Self {
+        Discovery { events, ..self }
+    }
+
+    pub(crate) fn is_self(&self, fingerprint: &Fingerprint) -> bool {
+        fingerprint == self.announcement.device_info().fingerprint()
+    }
+
+    /// What we register with peers as
+    pub(crate) fn legacy_register(&self) -> LegacyRegister {
+        legacy_register(&self.announcement)
+    }
+
+    /// Record peer, printing it if new
+    pub(crate) fn add_peer(&self, peer_info: PeerInfo) {
+        let mut peers = self.peers.lock().expect("peers lock poisoned");
+        let peer_fingerprint = peer_info.device_info.fingerprint().clone();
+        if !peers.contains_key(&peer_fingerprint) {
+            match self.format {
+                OutputFormat::Text => println!("New peer: {}", &peer_info),
+                OutputFormat::Jsonl => println!(
+                    "{}",
+                    serde_json::to_string(&peer_info).expect("fix this serialization")
+                ),
+                OutputFormat::Json => {}
+            }
+            self.events.emit(Event::PeerSeen {
+                peer: peer_info.clone(),
+            });
+        } else {
+            // println!("Updaing peer {:?}", peer_fingerprint);
+        }
+        self.unsaved_peers
+            .lock()
+            .expect("peers lock poisoned")
+            .insert(peer_fingerprint.clone(), peer_info.clone());
+        peers.insert(peer_fingerprint, peer_info);
+    }
+
+    /// Forget peer that said goodbye, if it did so from where we know it.
+    /// Known peers are kept, only the live table is affected.
+    pub(crate) fn remove_peer(&self, fingerprint: &Fingerprint, origin: IpAddr) {
+        let mut peers = self.peers.lock().expect("peers lock poisoned");
+        if peers
+            .get(fingerprint)
+            .is_none_or(|peer_info| peer_info.address != origin)
+        {
+            return;
+        }
+        let Some(peer_info) = peers.remove(fingerprint) else {
+            return;
+        };
+        if let OutputFormat::Text = self.format {
+            println!("Peer left: {peer_info}");
+        }
+        self.events.emit(Event::PeerLeft {
+            fingerprint: fingerprint.clone(),
+        });
+    }
+
+    /// Edit known peers, remembering those seen since last saved first.
+    /// Edits go through here while discovery runs, so saving peers seen doesn't undo them.
+    pub(crate) fn update_known_peers<T>(&self, update: impl FnOnce(&mut Vec<KnownPeer>) -> T) -> T {
+        let mut unsaved_peers = self.unsaved_peers.lock().expect("peers lock poisoned");
+        let mut known_peers = load_known_peers();
+        let now = unix_now();
+        for peer_info in std::mem::take(&mut *unsaved_peers).into_values() {
+            remember_peer(&mut known_peers, peer_info, now);
+        }
+        let outcome = update(&mut known_peers);
+        save_known_peers(known_peers);
+        outcome
+    }
+
+    /// Remember peers seen since last saved
+    pub(crate) fn save_peers(&self) {
+        self.update_known_peers(|_| ())
+    }
+}
+
+/// Routes of the http discovery api (register)
+pub fn register_routes(discovery: Arc<Discovery>) -> Router {
+    Router::new()
+        .route("/api/localsend/v2/register", post(register))
+        .with_state(discovery)
+}
+
+/// Peer registering itself with us, in reply to our announcement or while scanning.
+/// We learn about it, and it learns about us.
+async fn register(
+    State(discovery): State<Arc<Discovery>>,
+    ConnectInfo(peer_address): ConnectInfo<SocketAddr>,
+    body: Bytes,
+) -> Result<Json<LegacyRegisterResponse>, ApiError> {
+    let legacy_register: LegacyRegister = serde_json::from_slice(&body)
+        .map_err(|error| ApiError::new(400, format!("Invalid register request: {error}")))?;
+    if !discovery.is_self(legacy_register.device_info().fingerprint()) {
+        discovery.add_peer(PeerInfo {
+            device_info: legacy_register.device_info().clone(),
+            address: peer_address.ip().to_canonical(),
+            port: *legacy_register.port(),
+            protocol: *legacy_register.protocol(),
+            version: *legacy_register.version(),
+            download_mode: legacy_register.download().unwrap_or(false),
+            interface: interface_of(peer_address.ip().to_canonical()),
+            scope_id: link_local_scope(peer_address),
+        });
+    }
+    let announcement = &discovery.announcement;
+    Ok(Json(LegacyRegisterResponse::new(
+        Version::default(),
+        announcement.device_info().clone(),
+        *announcement.download(),
+    )))
+}
+
+/// Listen for peers announcing or responding over multicast, until stopped.
+/// If announce interval is given, we announce ourselves as often,
+/// and answer announcements (on given runtime), so peers discover us too.
+/// Returns all peers known by then, including those that registered over http.
+pub(crate) fn listen_broadcasts(
+    discovery: Arc<Discovery>,
+    rt_handle: Handle,
+    socket: Box<dyn MulticastChannel>,
+    announce_interval: Option<Duration>,
+    stop: CancellationToken,
+) -> PeersMap {
+    eprintln!("Listening for broadcasts!");
+    let respond = announce_interval.is_some();
+    let announce_string =
+        serde_json::to_string(&MulticastAnnounce::from(discovery.announcement.clone()))
+            .expect("fix this serialization");
+    let mut next_announce = Instant::now();
+    while !stop.is_cancelled() {
+        if let Some(announce_interval) = announce_interval {
+            if Instant::now() >= next_announce {
+                if let Err(e) = socket.send(announce_string.as_bytes()) {
+                    dbg!(e);
+                }
+                next_announce += announce_interval;
+            }
+        }
+        // Socket read timeout lets us notice the deadline when no one is talking
+        let Ok(datagram) = socket.receive() else {
+            continue;
+        };
+        let peer_address = datagram.origin;
+        let Ok(message_string) = String::from_utf8(datagram.data) else {
+            dbg!("Message not utf8", peer_address);
             continue;
         };
-        let peer_address = udp_message.origin_address.ip();
-        let message_string =
-            String::from_utf8(udp_message.data).expect("Message should be valid utf8 string");
-        let Ok(multicast_message) = serde_json::from_str(&message_string) else {
+        if let Ok(departure) = serde_json::from_str::<Departure>(&message_string) {
+            discovery.remove_peer(&departure.fingerprint, peer_address);
+            continue;
+        }
+        let Some((multicast_common, version, is_announce)) = parse_multicast(&message_string)
+        else {
             dbg!("Unrecognised message", peer_address, message_string);
             continue;
         };
-        let multicast_common: MulticastCommon = match multicast_message {
-            MulticastMessage::Announce(announce) => announce.multicast_common().clone(),
-            MulticastMessage::Response(response) => response.multicast_common().clone(),
+        if discovery.is_self(multicast_common.device_info().fingerprint()) {
+            continue;
+        }
+        let peer_download_mode = if let Some(prefer_download_mode) = multicast_common.download() {
+            *prefer_download_mode
+        } else {
+            false
+        };
+        let peer_info = PeerInfo {
+            device_info: multicast_common.device_info().clone(),
+            address: peer_address,
+            port: *multicast_common.port(),
+            protocol: *multicast_common.protocol(),
+            version,
+            download_mode: peer_download_mode,
+            interface: datagram.interface,
+            scope_id: datagram.scope_id,
+        };
+        if respond && is_announce {
+            rt_handle.spawn(respond_to_announce(
+                discovery.announcement.clone(),
+                peer_info.clone(),
+                stop.clone(),
+            ));
+        }
+        discovery.add_peer(peer_info);
+    }
+    // Peers that announce themselves are told we are going
+    if announce_interval.is_some() {
+        let departure = Departure {
+            fingerprint: discovery.announcement.device_info().fingerprint().clone(),
+            departing: serde_bool::True,
         };
-        let peer_fingerprint = multicast_common.device_info().fingerprint();
-        if peer_fingerprint != device_info.fingerprint() {
-            let peer_download_mode = if let Some(prefer_download_mode) = multicast_common.download()
-            {
-                *prefer_download_mode
-            } else {
-                false
-            };
-            let peer_info = PeerInfo {
-                device_info: multicast_common.device_info().clone(),
-                address: *peer_address,
-                port: *multicast_common.port(),
-                protocol: *multicast_common.protocol(),
-                download_mode: peer_download_mode,
-            };
-            if !peers.contains_key(peer_fingerprint) {
-                println!("New peer: {}", &peer_info);
-            } else {
-                // println!("Updaing peer {:?}", peer_fingerprint);
+        let departure_string = serde_json::to_string(&departure).expect("fix this serialization");
+        if let Err(e) = socket.send(departure_string.as_bytes()) {
+            dbg!(e);
+        }
+    }
+    discovery.peers.lock().expect("peers lock poisoned").clone()
+}
+
+/// Multicast message of either protocol version, with the version and whether it announces.
+/// v1 peers don't tell their version, and are known by their older messages.
+fn parse_multicast(message_string: &str) -> Option<(MulticastCommon, Version, bool)> {
+    if let Ok(multicast_message) = serde_json::from_str(message_string) {
+        return Some(match multicast_message {
+            MulticastMessage::Announce(announce) => (
+                announce.multicast_common().clone(),
+                *announce.version(),
+                true,
+            ),
+            MulticastMessage::Response(response) => (
+                response.multicast_common().clone(),
+                *response.version(),
+                false,
+            ),
+        });
+    }
+    let v1_message: v1::MulticastMessage = serde_json::from_str(message_string).ok()?;
+    let is_announce = v1_message.announcement();
+    Some((v1_message.into(), Version::V1_0, is_announce))
+}
+
+/// Reply to a peer's announcement by registering with it (http),
+/// falling back to a multicast response if that fails.
+/// Once stopped, we are leaving and don't reply anymore.
+async fn respond_to_announce(
+    announcement: MulticastCommon,
+    peer_info: PeerInfo,
+    stop: CancellationToken,
+) {
+    // Announced fingerprint is what peer's certificate should hash to (https)
+    let registered = match PeerAddress::from(&peer_info).client() {
+        Ok(client) => {
+            let legacy_register = legacy_register(&announcement);
+            let register =
+                tokio::time::timeout(REGISTER_TIMEOUT, client.register(&legacy_register));
+            tokio::select! {
+                registered = register => matches!(registered, Ok(Ok(_))),
+                () = stop.cancelled() => return,
             }
-            peers.insert(peer_fingerprint.clone(), peer_info);
+        }
+        Err(_) => false,
+    };
+    if !registered && !stop.is_cancelled() {
+        let response_string = multicast_response(announcement, peer_info.version);
+        // Same ip version as the group peer announced over
+        let sent = match peer_info.address {
+            IpAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|socket| {
+                socket.send_to(response_string.as_bytes(), (MULTICAST_IP, LOCALSEND_PORT))
+            }),
+            IpAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).and_then(|socket| {
+                socket.send_to(response_string.as_bytes(), (MULTICAST_IPV6, LOCALSEND_PORT))
+            }),
         };
+        if let Err(e) = sent {
+            dbg!(e);
+        }
     }
 }
 
-fn announce_broadcast(device_info: DeviceInfo, interval: u64) {
-    println!("Announcing ourselves over multicast: {device_info}");
-    let mulicast_address = SocketAddrV4::new(MULTICAST_IP, LOCALSEND_PORT);
-    let socket = MulticastSocket::all_interfaces(mulicast_address).unwrap();
+/// Multicast response to an announcement, in peer's own protocol version.
+/// v1 peers don't parse v2 responses.
+fn multicast_response(announcement: MulticastCommon, peer_version: Version) -> String {
+    if peer_version < Version::V2_0 {
+        let response = v1::MulticastMessage::new(announcement.device_info().clone().into(), false);
+        serde_json::to_string(&response)
+    } else {
+        serde_json::to_string(&MulticastResponse::from(announcement))
+    }
+    .expect("fix this serialization")
+}
 
-    let self_announce = MulticastAnnounce::from(MulticastCommon::new(
-        device_info,
-        LOCALSEND_PORT.into(),
-        Protocol::Http,
-        Some(true),
-    ));
+/// Announce ourselves over multicast groups and interfaces in scope, repeating every interval (seconds).
+/// The announcement carries our port, protocol and whether we serve the download api.
+pub fn announce_broadcast(announcement: MulticastCommon, interval: u64, scope: MulticastScope) {
+    eprintln!(
+        "Announcing ourselves over multicast: {}",
+        announcement.device_info()
+    );
+    let sockets = join_multicast_groups(&scope);
+
+    let self_announce = MulticastAnnounce::from(announcement);
     let announce_string = serde_json::to_string(&self_announce).expect("fix this serialization");
     let announce_bytes = announce_string.as_bytes();
     loop {
-        let result = socket.send(announce_bytes, &Interface::Default);
-        if let Err(e) = result {
-            dbg!(e);
+        for socket in &sockets {
+            let result = socket.send(announce_bytes);
+            if let Err(e) = result {
+                dbg!(e);
+            }
         }
         thread::sleep(Duration::from_secs(interval));
     }
 }
 
+/// What we register as when not listening (ex sending or downloading alone)
+pub(crate) fn standalone_register() -> LegacyRegister {
+    LegacyRegister::new(
+        Version::default(),
+        load_state().device_info,
+        LOCALSEND_PORT.into(),
+        Protocol::Https,
+        Some(false),
+    )
+}
+
+fn legacy_register(announcement: &MulticastCommon) -> LegacyRegister {
+    LegacyRegister::new(
+        Version::default(),
+        announcement.device_info().clone(),
+        *announcement.port(),
+        *announcement.protocol(),
+        *announcement.download(),
+    )
+}
+
 type PeersMap = HashMap<Fingerprint, PeerInfo>;
 
-#[derive(Display, Debug)]
-#[display("{}", self.terminal_display())]
+/// Multicast goodbye of an instance that stops announcing, our addition to the protocol.
+/// Other implementations don't parse it, and forget us once our announcements stop.
+#[derive(Debug, Serialize, Deserialize)]
+struct Departure {
+    fingerprint: Fingerprint,
+    departing: serde_bool::True,
+}
+
+/// How long to wait for a peer to accept our registration, before multicasting instead
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to look for a peer given by alias or fingerprint, when not known already
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
 
-struct PeerInfo {
+#[derive(Display, Debug, Clone, Serialize, Deserialize)]
+#[display("{}", self.terminal_display())]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PeerInfo {
+    #[serde(flatten)]
     pub device_info: DeviceInfo,
-    pub address: Ipv4Addr,
+    pub address: IpAddr,
     pub port: Port,
     pub protocol: Protocol,
+    /// Protocol version peer speaks
+    #[serde(default)]
+    pub version: Version,
     pub download_mode: bool,
+    /// Network interface peer was seen on, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// Scope of peer's address, if link-local
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope_id: Option<u32>,
 }
 
 impl PeerInfo {
     fn terminal_display(&self) -> String {
-        let full_address = format!("{}:{}", self.address, self.port);
+        let full_address = SocketAddr::new(self.address, self.port.dissolve()).to_string();
         let url = format!(
             "{}://{}",
             self.protocol.to_string().to_lowercase(),
             full_address
         );
         let link = terminal_link::Link::new(&full_address, &url);
-        format!(
+        let mut display = format!(
             "{} @{} {}",
             self.device_info,
             link,
             if self.download_mode { "📥" } else { "📤" }
+        );
+        if let Some(interface) = &self.interface {
+            display.push_str(&format!(" on {interface}"));
+        }
+        display
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use localsend_lib_types::messages::{
+        common_fields::{DeviceInfo, DeviceType, Fingerprint, Protocol, Version},
+        discover::{MulticastAnnounce, MulticastCommon},
+        v1,
+    };
+
+    use super::{multicast_response, parse_multicast, Departure, Discovery, PeerInfo};
+    use crate::args::OutputFormat;
+
+    fn device_info(fingerprint: &str) -> DeviceInfo {
+        DeviceInfo::new(
+            "Nice Orange".to_string().into(),
+            None,
+            DeviceType::Headless,
+            Fingerprint::new(fingerprint.to_string()),
         )
     }
+
+    #[test]
+    fn departure_forgets_peer_only_from_its_address() {
+        let announcement =
+            MulticastCommon::new(device_info("ours"), 53317.into(), Protocol::Https, None);
+        // Announcements aren't goodbyes
+        let announce_string =
+            serde_json::to_string(&MulticastAnnounce::from(announcement.clone())).unwrap();
+        assert!(serde_json::from_str::<Departure>(&announce_string).is_err());
+
+        let discovery = Discovery::new(announcement, OutputFormat::Json);
+        let peer_address = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+        discovery.add_peer(PeerInfo {
+            device_info: device_info("theirs"),
+            address: peer_address,
+            port: 53317.into(),
+            protocol: Protocol::Https,
+            version: Version::default(),
+            download_mode: false,
+            interface: None,
+            scope_id: None,
+        });
+        let departure: Departure =
+            serde_json::from_str(r#"{"fingerprint":"theirs","departing":true}"#).unwrap();
+        let has_peer = || {
+            discovery
+                .peers
+                .lock()
+                .unwrap()
+                .contains_key(&departure.fingerprint)
+        };
+        // Anyone can send a goodbye, it only counts from where the peer is
+        discovery.remove_peer(
+            &departure.fingerprint,
+            IpAddr::V4(Ipv4Addr::new(192, 168, 1, 3)),
+        );
+        assert!(has_peer());
+        discovery.remove_peer(&departure.fingerprint, peer_address);
+        assert!(!has_peer());
+    }
+
+    #[test]
+    fn announcements_answered_in_peer_version() {
+        let announcement =
+            MulticastCommon::new(device_info("ours"), 53317.into(), Protocol::Https, None);
+        let response = multicast_response(announcement.clone(), Version::V1_0);
+        let v1_response: v1::MulticastMessage = serde_json::from_str(&response).unwrap();
+        assert!(!v1_response.announcement());
+        assert_eq!(
+            parse_multicast(&response)
+                .map(|(_common, version, is_announce)| (version, is_announce)),
+            Some((Version::V1_0, false))
+        );
+        let response = multicast_response(announcement, Version::V2_0);
+        assert_eq!(
+            parse_multicast(&response)
+                .map(|(_common, version, is_announce)| (version, is_announce)),
+            Some((Version::default(), false))
+        );
+    }
 }
@@ -1,32 +1,53 @@
 use std::path::{Path, PathBuf};
 
 use crate::args::DownloadArgs;
-use crate::constants::FOX_USER_AGENT;
-use crate::utils::{ask_confirm, dbgr, sanitize_relative_path};
+use crate::discover::{resolve_peer, standalone_register};
+use crate::utils::{
+    ask_confirm, dbgr, file_times, retry_too_many_requests, sanitize_relative_path,
+};
 use compounderr::compose_errors as funsie_errors;
-use localsend_lib_types::messages::common_fields::{FileInfo, FilesInfoMap, SessionId};
-use localsend_lib_types::messages::download::PrepareDownloadResponse;
-use reqwest::Url;
-use reqwest::{Client, Error as ReqwestError};
-use serde_json::Error as SerdeJsonError;
+use localsend_client::{errors::ClientError, LocalSendClient};
+use localsend_lib_types::messages::common_fields::{FileInfo, FilesInfoMap, Protocol, SessionId};
 use std::io::Error as IoError;
-use tokio::io::AsyncWriteExt;
-use url::ParseError as UrlParseError;
 
 /// Download files from given server using pin if required
-// #[tokio::main(flavor = "current_thread")]
 pub fn download(download_args: DownloadArgs) {
-    let base_url = Url::parse(&format!(
-        "http://{}:{}",
+    let protocol = if download_args.http() {
+        Protocol::Http
+    } else {
+        Protocol::Https
+    };
+    let peer_address = match resolve_peer(
         download_args.sender(),
-        download_args.port()
-    ))
-    .expect("Ipv4addr and port should be parseable");
+        download_args.port(),
+        protocol,
+        *download_args.protocol_version(),
+    ) {
+        Ok(peer_address) => peer_address,
+        Err(error) => {
+            println!("{error}");
+            return;
+        }
+    };
+    let client = peer_address.client().inspect_err(dbgr).unwrap();
+    let rt = tokio::runtime::Runtime::new().inspect_err(dbgr).unwrap();
+    if let Err(error) = rt.block_on(peer_address.identify(&client, &standalone_register())) {
+        println!("Could not identify sender: {error}");
+        return;
+    }
     println!("Querying for files info");
+    let pin = download_args.pin().as_deref();
     let prepare_download_response =
-        prepare_download_request(&base_url, download_args.pin().as_deref())
-            .inspect_err(dbgr)
-            .unwrap();
+        match rt.block_on(retry_too_many_requests(|| client.prepare_download(pin))) {
+            Ok(prepare_download_response) => prepare_download_response,
+            Err(error) => {
+                println!("Sender did not share files: {error}");
+                if error.is_pin_required() && pin.is_none() {
+                    println!("Pass the pin shown by sender using --pin");
+                }
+                return;
+            }
+        };
     let files_map = prepare_download_response.files();
     let session_id = prepare_download_response.session_id();
     let destination = download_args.destination();
@@ -46,101 +67,46 @@ pub fn download(download_args: DownloadArgs) {
     if !confirm {
         return;
     };
-    engage_downloader(
-        &base_url,
-        download_args.pin().as_deref(),
-        session_id,
-        files_map,
-        download_args.destination(),
-    )
-    .unwrap();
-}
-
-#[funsie_errors]
-#[errorset(ReqwestError, SerdeJsonError)]
-pub fn prepare_download_request(
-    base_url: &Url,
-    pin: Option<&str>,
-) -> Result<PrepareDownloadResponse, _> {
-    let client = reqwest::blocking::Client::builder()
-        .user_agent(FOX_USER_AGENT)
-        .build()
-        .unwrap();
-
-    let pre_download_url = base_url
-        .join("/api/localsend/v2/prepare-download")
-        .expect("prechecked path shouldn't error");
-
-    let pre_download_req = if let Some(pin) = pin {
-        client.post(pre_download_url).query(&[("pin", pin)])
-    } else {
-        client.post(pre_download_url)
-    };
-
-    let response = pre_download_req.send()?.error_for_status()?;
-    let response_text = response.text()?;
-    let prepare_download_response = serde_json::from_str(&response_text)?;
-    // dbg!(&prepare_download_response);
-    Ok(prepare_download_response)
-}
-
-#[funsie_errors]
-#[errorset(IoError)]
-pub fn engage_downloader(
-    base_url: &Url,
-    pin: Option<&str>,
-    session_id: &SessionId,
-    files_info_map: &FilesInfoMap,
-    destination: &Path,
-) -> Result<(), _> {
-    // Create the runtime
-    let rt = tokio::runtime::Runtime::new()?;
-
-    // Run the main download_files async function on the runtime
     rt.block_on(async {
         println!("Downloading files");
-        download_files(base_url, pin, session_id, files_info_map, destination).await
+        download_files(&client, session_id, files_map, destination).await
     });
-
-    Ok(())
 }
 
 async fn download_files(
-    base_url: &Url,
-    pin: Option<&str>,
+    client: &LocalSendClient,
     session_id: &SessionId,
     files_info_map: &FilesInfoMap,
     destination: &Path,
 ) {
-    let client = Client::new();
     let mut joinset = tokio::task::JoinSet::new();
-    let _abort_handles: Vec<_> = files_info_map
-        .as_ref()
-        .iter()
-        .map(|(_file_id, file_info)| {
-            joinset.spawn(download_silngle_file(
-                client.clone(),
-                base_url.clone(),
-                pin.map(str::to_string),
-                session_id.clone(),
-                file_info.clone(),
-                destination.to_path_buf(),
-            ))
-        })
-        .collect();
-    let results = joinset.join_all().await;
-    println!("Results (unordered): {:?}", results);
+    for file_info in files_info_map.as_ref().values() {
+        let file_name = file_info.file_name().clone();
+        let download = download_silngle_file(
+            client.clone(),
+            session_id.clone(),
+            file_info.clone(),
+            destination.to_path_buf(),
+        );
+        joinset.spawn(async move { (file_name, download.await) });
+    }
+    for (file_name, result) in joinset.join_all().await {
+        match result {
+            Ok(()) => println!("received {file_name}"),
+            Err(error) => println!("failed {file_name}: {error}"),
+        }
+    }
 }
 
 /// Download given file as per localsend protocol.
 /// This func could run as a separate task, maybe even on a differnt thread.
 /// So we take ownership of our inputs.
+/// File is written to a `.part` file first, next to a sidecar recording what it is a part of.
+/// An interrupted download of the same file is resumed, and moved in place once complete.
 #[funsie_errors]
-#[errorset(UrlParseError, ReqwestError, IoError)]
+#[errorset(ClientError, IoError)]
 async fn download_silngle_file(
-    client: Client,
-    base_url: Url,
-    pin: Option<String>,
+    client: LocalSendClient,
     session_id: SessionId,
     file_info: FileInfo,
     destination: PathBuf,
@@ -150,20 +116,75 @@ async fn download_silngle_file(
     if let Some(parent_dir) = full_file_path.parent() {
         tokio::fs::create_dir_all(parent_dir).await?;
     };
-    let file_url = base_url.join("/api/localsend/v2/download")?;
-    let mut query = Vec::from([
-        ("sessionId", session_id.to_string()),
-        ("fileId", file_info.id().to_string()),
-    ]);
-    if let Some(pin) = pin {
-        query.push(("pin", pin));
+    let part_path = with_suffix(&full_file_path, ".part");
+    let sidecar_path = with_suffix(&full_file_path, ".part.json");
+    let resuming = is_part_of(&sidecar_path, &file_info).await;
+    if resuming {
+        println!("resuming {:?}", full_file_path);
+    } else {
+        println!("writing {:?}", full_file_path);
+        let sidecar = serde_json::to_vec(&file_info).expect("FileInfo should serialize");
+        tokio::fs::write(&sidecar_path, sidecar).await?;
+    }
+    let mut part_file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(!resuming)
+        .open(&part_path)
+        .await?;
+    let downloaded = client
+        .resume_download(&session_id, &file_info, &mut part_file)
+        .await;
+    let part_file = part_file.into_std().await;
+    if downloaded.is_ok() {
+        // Keep times the sender had, a failure here doesn't spoil the download
+        if let Err(error) = part_file.set_times(file_times(&file_info)) {
+            println!("Could not set times of {:?}: {error}", full_file_path);
+        }
+    }
+    drop(part_file);
+    let keep_for_resume = match &downloaded {
+        Ok(_) => {
+            tokio::fs::rename(&part_path, &full_file_path).await?;
+            false
+        }
+        // Corrupt, start over next time
+        Err(ClientError::Verification { .. }) => false,
+        Err(_) => tokio::fs::metadata(&part_path)
+            .await
+            .is_ok_and(|metadata| metadata.len() > 0),
     };
-    let mut res = client.get(file_url).query(&query).send().await?;
-    res.error_for_status_ref()?;
-    println!("writing {:?}", full_file_path);
-    let mut open_file = tokio::fs::File::create(full_file_path).await?;
-    while let Some(chunk) = res.chunk().await? {
-        open_file.write_all(&chunk).await?;
+    if !keep_for_resume {
+        // Don't leave behind empty or corrupt files
+        let _ = tokio::fs::remove_file(&part_path).await;
+        let _ = tokio::fs::remove_file(&sidecar_path).await;
     }
+    downloaded?;
     Ok(())
 }
+
+/// Path with suffix appended to file name, ex `a.txt` -> `a.txt.part`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Whether sidecar records a partial download of this very file.
+/// Same id (same share), or same advertised sha256 (same contents, shared again).
+async fn is_part_of(sidecar_path: &Path, file_info: &FileInfo) -> bool {
+    let Ok(sidecar) = tokio::fs::read(sidecar_path).await else {
+        return false;
+    };
+    let Ok(part_of) = serde_json::from_slice::<FileInfo>(&sidecar) else {
+        return false;
+    };
+    let same_contents = match (part_of.sha_256(), file_info.sha_256()) {
+        (Some(part_of_sha256), Some(sha256)) => part_of_sha256.matches(sha256),
+        _ => false,
+    };
+    part_of.file_name() == file_info.file_name()
+        && part_of.size() == file_info.size()
+        && (part_of.id() == file_info.id() || same_contents)
+}
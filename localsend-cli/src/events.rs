@@ -0,0 +1,132 @@
+//! Events of a long running instance (daemon), for control api subscribers
+use localsend_lib_types::messages::common_fields::{Fingerprint, SessionId};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{discover::PeerInfo, session::IncomingSession};
+
+/// Something that happened, to peers or transfers.
+/// Incoming sessions go by session id, outgoing transfers (sends) by transfer id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Peer seen for the first time since we started
+    PeerSeen {
+        peer: PeerInfo,
+    },
+    /// Peer said goodbye, it stopped announcing itself
+    PeerLeft {
+        fingerprint: Fingerprint,
+    },
+    /// Peer wants to send us files. Pending ones wait to be accepted or rejected.
+    SessionIncoming {
+        session: IncomingSession,
+    },
+    SessionAccepted {
+        session_id: SessionId,
+    },
+    SessionRejected {
+        session_id: SessionId,
+    },
+    FileReceived {
+        session_id: SessionId,
+        file_name: String,
+    },
+    FileReceiveFailed {
+        session_id: SessionId,
+        file_name: String,
+        reason: String,
+    },
+    /// All accepted files received
+    SessionFinished {
+        session_id: SessionId,
+    },
+    SessionCancelled {
+        session_id: SessionId,
+    },
+    /// Dropped after going idle for too long
+    SessionExpired {
+        session_id: SessionId,
+    },
+    SendStarted {
+        transfer_id: String,
+        peer: String,
+    },
+    /// Receiver accepted some (or all) of the files offered
+    SendAccepted {
+        transfer_id: String,
+        session_id: SessionId,
+        files: usize,
+    },
+    FileSent {
+        transfer_id: String,
+        file_name: String,
+    },
+    FileSendFailed {
+        transfer_id: String,
+        file_name: String,
+        reason: String,
+    },
+    SendFinished {
+        transfer_id: String,
+    },
+    SendFailed {
+        transfer_id: String,
+        reason: String,
+    },
+    SendCancelled {
+        transfer_id: String,
+    },
+}
+
+impl Event {
+    /// Outgoing transfer the event is about, if any
+    pub fn transfer_id(&self) -> Option<&str> {
+        match self {
+            Event::SendStarted { transfer_id, .. }
+            | Event::SendAccepted { transfer_id, .. }
+            | Event::FileSent { transfer_id, .. }
+            | Event::FileSendFailed { transfer_id, .. }
+            | Event::SendFinished { transfer_id }
+            | Event::SendFailed { transfer_id, .. }
+            | Event::SendCancelled { transfer_id } => Some(transfer_id),
+            _ => None,
+        }
+    }
+
+    /// Whether this ends an outgoing transfer
+    pub fn ends_transfer(&self) -> bool {
+        matches!(
+            self,
+            Event::SendFinished { .. } | Event::SendFailed { .. } | Event::SendCancelled { .. }
+        )
+    }
+}
+
+/// Passes events on to whoever subscribed at the time
+#[derive(Clone)]
+pub struct Events(broadcast::Sender<Event>);
+
+impl Events {
+    pub fn new() -> Self {
+        Events(broadcast::channel(EVENTS_BACKLOG).0)
+    }
+
+    pub fn emit(&self, event: Event) {
+        // No subscribers is fine
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events::new()
+    }
+}
+
+/// Events kept for slow subscribers, before they miss some
+const EVENTS_BACKLOG: usize = 256;
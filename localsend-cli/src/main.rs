@@ -1,13 +1,33 @@
 mod args;
+mod config;
 mod constants;
+#[cfg(unix)]
+mod control;
+mod daemon;
 mod discover;
 mod download;
+mod events;
+mod multicast;
+mod peers;
+mod pin;
+mod policy;
+mod receive;
+mod scan;
+mod send;
+mod server;
+mod session;
+mod share;
 mod state;
 mod utils;
 
 use args::{Args, SubCommand};
+use daemon::daemon;
 use discover::discover;
 use download::download;
+use peers::peers;
+use receive::receive;
+use send::send;
+use share::share;
 
 fn main() {
     let args: Args = argh::from_env();
@@ -15,5 +35,10 @@ fn main() {
     match args.subcommand {
         SubCommand::Discover(discover_args) => discover(discover_args),
         SubCommand::Download(download_args) => download(download_args),
+        SubCommand::Receive(receive_args) => receive(receive_args),
+        SubCommand::Send(send_args) => send(send_args),
+        SubCommand::Share(share_args) => share(share_args),
+        SubCommand::Daemon(daemon_args) => daemon(daemon_args),
+        SubCommand::Peers(peers_args) => peers(peers_args),
     }
 }
@@ -0,0 +1,281 @@
+//! Multicast sockets we discover peers over.
+//! Ipv4 goes through multicast-socket, ipv6 (which it doesn't support) through socket2.
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    time::Duration,
+};
+
+use if_addrs::{get_if_addrs, IfAddr, Interface as NetworkInterface};
+use multicast_socket::{Interface, MulticastOptions, MulticastSocket};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+use crate::{
+    constants::{LOCALSEND_PORT, MULTICAST_IP, MULTICAST_IPV6},
+    utils::link_local_scope,
+};
+
+/// Which multicast groups, on which network interfaces, to discover over
+#[derive(Debug, Clone, Default)]
+pub struct MulticastScope {
+    /// Also use the ipv6 group
+    pub ipv6: bool,
+    /// Interfaces (name or ip) to use, all if none given
+    pub interfaces: Vec<String>,
+    /// Interfaces (name or ip) not to use
+    pub exclude_interfaces: Vec<String>,
+}
+
+impl MulticastScope {
+    fn is_filtered(&self) -> bool {
+        !self.interfaces.is_empty() || !self.exclude_interfaces.is_empty()
+    }
+
+    pub(crate) fn selects(&self, interface: &NetworkInterface) -> bool {
+        let named = |name_or_ip: &String| {
+            &interface.name == name_or_ip || &interface.ip().to_string() == name_or_ip
+        };
+        (self.interfaces.is_empty() || self.interfaces.iter().any(named))
+            && !self.exclude_interfaces.iter().any(named)
+    }
+}
+
+/// Message received over multicast
+pub struct Datagram {
+    pub data: Vec<u8>,
+    /// Who sent it
+    pub origin: IpAddr,
+    /// Scope of sender's address, if link-local
+    pub scope_id: Option<u32>,
+    /// Name of network interface it came in on, if known
+    pub interface: Option<String>,
+}
+
+/// Socket joined to a multicast group, sending to and receiving from it
+pub trait MulticastChannel: Send {
+    /// Next message on the selected interfaces.
+    /// Errors on read timeout too, so callers get a chance to stop listening.
+    fn receive(&self) -> io::Result<Datagram>;
+    /// Send message to the group, on the selected interfaces
+    fn send(&self, data: &[u8]) -> io::Result<()>;
+}
+
+/// Sockets joined to the localsend multicast group, and its ipv6 counterpart if asked for.
+/// Groups that can't be joined (ex: no such network) are reported and left out.
+pub fn join_multicast_groups(scope: &MulticastScope) -> Vec<Box<dyn MulticastChannel>> {
+    let mut sockets: Vec<Box<dyn MulticastChannel>> = Vec::new();
+    match MulticastV4Socket::join(MULTICAST_IP, LOCALSEND_PORT, scope) {
+        Ok(socket) => sockets.push(Box::new(socket)),
+        Err(error) => eprintln!("Could not join multicast group {MULTICAST_IP}: {error}"),
+    }
+    if scope.ipv6 {
+        match MulticastV6Socket::join(MULTICAST_IPV6, LOCALSEND_PORT, scope) {
+            Ok(socket) => sockets.push(Box::new(socket)),
+            Err(error) => eprintln!("Could not join multicast group {MULTICAST_IPV6}: {error}"),
+        }
+    }
+    sockets
+}
+
+/// Name of the network interface whose subnet has ip, if any
+pub fn interface_of(ip: IpAddr) -> Option<String> {
+    let interfaces = get_if_addrs().ok()?;
+    interfaces
+        .iter()
+        .find(|interface| in_subnet(interface, ip))
+        .map(|interface| interface.name.clone())
+}
+
+fn in_subnet(interface: &NetworkInterface, ip: IpAddr) -> bool {
+    match (&interface.addr, ip) {
+        (IfAddr::V4(address), IpAddr::V4(ip)) => {
+            let netmask = u32::from(address.netmask);
+            u32::from(address.ip) & netmask == u32::from(ip) & netmask
+        }
+        (IfAddr::V6(address), IpAddr::V6(ip)) => {
+            let netmask = u128::from(address.netmask);
+            u128::from(address.ip) & netmask == u128::from(ip) & netmask
+        }
+        _ => false,
+    }
+}
+
+/// Ipv4 multicast socket, joined to group on selected interfaces
+pub struct MulticastV4Socket {
+    socket: MulticastSocket,
+    scope: MulticastScope,
+    /// All interfaces of the system, to tell which one a message came in on
+    interfaces: Vec<NetworkInterface>,
+}
+
+impl MulticastV4Socket {
+    pub fn join(group: Ipv4Addr, port: u16, scope: &MulticastScope) -> io::Result<Self> {
+        let address = SocketAddrV4::new(group, port);
+        let interfaces = get_if_addrs()?;
+        let socket = if scope.is_filtered() {
+            let selected: Vec<Ipv4Addr> = interfaces
+                .iter()
+                .filter(|interface| scope.selects(interface))
+                .filter_map(|interface| match interface.ip() {
+                    IpAddr::V4(ip) => Some(ip),
+                    IpAddr::V6(_) => None,
+                })
+                .collect();
+            if selected.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no ipv4 interface selected",
+                ));
+            }
+            MulticastSocket::with_options(address, selected, MulticastOptions::default())?
+        } else {
+            MulticastSocket::all_interfaces(address)?
+        };
+        Ok(MulticastV4Socket {
+            socket,
+            scope: scope.clone(),
+            interfaces,
+        })
+    }
+
+    fn interface(&self, interface: Interface, origin: IpAddr) -> Option<&NetworkInterface> {
+        let mut interfaces = self.interfaces.iter();
+        match interface {
+            Interface::Index(index) => {
+                interfaces.find(|interface| interface.index == Some(index as u32))
+            }
+            Interface::Ip(ip) => interfaces.find(|interface| interface.ip() == IpAddr::V4(ip)),
+            Interface::Default => interfaces.find(|interface| in_subnet(interface, origin)),
+        }
+    }
+}
+
+impl MulticastChannel for MulticastV4Socket {
+    fn receive(&self) -> io::Result<Datagram> {
+        loop {
+            let message = self.socket.receive()?;
+            let origin = IpAddr::V4(*message.origin_address.ip());
+            let interface = self.interface(message.interface, origin);
+            // Group membership is per host, so we may hear groups joined on other interfaces
+            if interface.is_some_and(|interface| !self.scope.selects(interface)) {
+                continue;
+            }
+            return Ok(Datagram {
+                data: message.data,
+                origin,
+                scope_id: None,
+                interface: interface.map(|interface| interface.name.clone()),
+            });
+        }
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        if self.scope.is_filtered() {
+            self.socket.broadcast(data)
+        } else {
+            self.socket.send(data, &Interface::Default).map(|_sent| ())
+        }
+    }
+}
+
+/// Ipv6 multicast socket, joined to group on selected interfaces
+pub struct MulticastV6Socket {
+    socket: UdpSocket,
+    group: SocketAddrV6,
+    scope: MulticastScope,
+    /// All interfaces of the system, to tell which one a message came in on
+    interfaces: Vec<NetworkInterface>,
+    /// Indexes of interfaces joined on, none meaning the default one
+    joined: Vec<u32>,
+}
+
+impl MulticastV6Socket {
+    pub fn join(group: Ipv6Addr, port: u16, scope: &MulticastScope) -> io::Result<Self> {
+        let interfaces = get_if_addrs()?;
+        let mut joined: Vec<u32> = Vec::new();
+        if scope.is_filtered() {
+            for interface in interfaces
+                .iter()
+                .filter(|interface| scope.selects(interface))
+            {
+                if let (IpAddr::V6(_), Some(index)) = (interface.ip(), interface.index) {
+                    if !joined.contains(&index) {
+                        joined.push(index);
+                    }
+                }
+            }
+            if joined.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no ipv6 interface selected",
+                ));
+            }
+        }
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(true)?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        // Same as multicast-socket defaults
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        socket.set_multicast_loop_v6(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        if joined.is_empty() {
+            socket.join_multicast_v6(&group, 0)?;
+        }
+        for index in &joined {
+            socket.join_multicast_v6(&group, *index)?;
+        }
+        Ok(MulticastV6Socket {
+            socket: socket.into(),
+            group: SocketAddrV6::new(group, port, 0, 0),
+            scope: scope.clone(),
+            interfaces,
+            joined,
+        })
+    }
+}
+
+impl MulticastChannel for MulticastV6Socket {
+    fn receive(&self) -> io::Result<Datagram> {
+        loop {
+            let mut buf = vec![0; 4096];
+            let (received, origin_address) = self.socket.recv_from(&mut buf)?;
+            buf.truncate(received);
+            let scope_id = match origin_address {
+                SocketAddr::V6(origin_address) => origin_address.scope_id(),
+                SocketAddr::V4(_) => 0,
+            };
+            let origin = origin_address.ip();
+            // Link-local senders tell the interface by scope, others by subnet
+            let interface = self.interfaces.iter().find(|interface| {
+                if scope_id != 0 {
+                    interface.index == Some(scope_id)
+                } else {
+                    in_subnet(interface, origin)
+                }
+            });
+            if interface.is_some_and(|interface| !self.scope.selects(interface)) {
+                continue;
+            }
+            return Ok(Datagram {
+                data: buf,
+                origin,
+                scope_id: link_local_scope(origin_address),
+                interface: interface.map(|interface| interface.name.clone()),
+            });
+        }
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        if self.joined.is_empty() {
+            return self.socket.send_to(data, self.group).map(|_sent| ());
+        }
+        let socket = SockRef::from(&self.socket);
+        for index in &self.joined {
+            socket.set_multicast_if_v6(*index)?;
+            self.socket.send_to(data, self.group)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+//! Peers seen before, as kept in the state dir
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    args::{OutputFormat, PeersArgs, PeersCommand},
+    discover::{matching_peer, ResolvePeerError},
+    state::{load_known_peers, save_known_peers, unix_now, KnownPeer},
+};
+
+/// List, rename, forget or (un)trust peers seen before
+pub fn peers(peers_args: PeersArgs) {
+    let (peer, update) = match peers_args.command() {
+        PeersCommand::List(list_args) => {
+            return list_peers(current_peers(), *list_args.format());
+        }
+        PeersCommand::Rename(rename_args) => (
+            rename_args.peer(),
+            PeerUpdate::Rename {
+                nickname: rename_args.nickname().clone(),
+            },
+        ),
+        PeersCommand::Forget(forget_args) => (forget_args.peer(), PeerUpdate::Forget),
+        PeersCommand::Trust(trust_args) => (trust_args.peer(), PeerUpdate::Trust),
+        PeersCommand::Untrust(untrust_args) => (untrust_args.peer(), PeerUpdate::Untrust),
+    };
+    match update_known_peer(peer, update) {
+        Ok(outcome) => println!("{outcome}"),
+        Err(message) => println!("{message}"),
+    }
+}
+
+/// Edit of a known peer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "update", rename_all = "snake_case")]
+pub enum PeerUpdate {
+    /// Nickname to give, none to clear it
+    Rename {
+        nickname: Option<String>,
+    },
+    Forget,
+    Trust,
+    Untrust,
+}
+
+impl PeerUpdate {
+    /// Apply to the known peer given by alias, nickname or fingerprint (prefix),
+    /// describing the outcome
+    pub(crate) fn apply(
+        &self,
+        known_peers: &mut Vec<KnownPeer>,
+        peer: &str,
+    ) -> Result<String, String> {
+        let index = known_peer_index(peer, known_peers).map_err(|error| error.to_string())?;
+        match self {
+            PeerUpdate::Rename { nickname } => {
+                if let Some(nickname) = nickname {
+                    if names_other_peer(known_peers, index, nickname) {
+                        return Err(format!("{nickname:?} already names another peer"));
+                    }
+                }
+                let known_peer = &mut known_peers[index];
+                known_peer.nickname = nickname.clone();
+                Ok(format!(
+                    "{} is now known as {}",
+                    known_peer.fingerprint(),
+                    known_peer.name()
+                ))
+            }
+            PeerUpdate::Forget => {
+                let known_peer = known_peers.remove(index);
+                Ok(format!("Forgot {}", known_peer.name()))
+            }
+            PeerUpdate::Trust => {
+                known_peers[index].trusted = true;
+                Ok(format!("Trusting {}", known_peers[index].name()))
+            }
+            PeerUpdate::Untrust => {
+                known_peers[index].trusted = false;
+                Ok(format!("No longer trusting {}", known_peers[index].name()))
+            }
+        }
+    }
+}
+
+/// Known peers, including those a running daemon saw but didn't save yet
+fn current_peers() -> Vec<KnownPeer> {
+    #[cfg(unix)]
+    if let Some(mut control_client) = crate::control::ControlClient::connect() {
+        use crate::control::{Reply, Request};
+        let reply = control_client
+            .request(&Request::ListPeers)
+            .and_then(|()| control_client.read::<Reply>());
+        if let Ok(Reply::Peers { peers }) = reply {
+            return peers;
+        }
+    }
+    load_known_peers()
+}
+
+/// Apply update to the known peer given, through a running daemon (which saves peers itself),
+/// else to the state dir
+fn update_known_peer(peer: &str, update: PeerUpdate) -> Result<String, String> {
+    #[cfg(unix)]
+    if let Some(mut control_client) = crate::control::ControlClient::connect() {
+        use crate::control::{Reply, Request};
+        let request = Request::UpdatePeer {
+            peer: peer.to_string(),
+            update: update.clone(),
+        };
+        let reply = control_client
+            .request(&request)
+            .and_then(|()| control_client.read::<Reply>());
+        match reply {
+            Ok(Reply::PeerUpdated { outcome }) => return Ok(outcome),
+            Ok(Reply::Error { message }) => return Err(message),
+            _ => {}
+        }
+    }
+    let mut known_peers = load_known_peers();
+    let outcome = update.apply(&mut known_peers, peer)?;
+    save_known_peers(known_peers);
+    Ok(outcome)
+}
+
+/// Index of the one known peer given by alias, nickname or fingerprint (prefix)
+fn known_peer_index(peer: &str, known_peers: &[KnownPeer]) -> Result<usize, ResolvePeerError> {
+    let known_peer = matching_peer(peer, known_peers.iter())?
+        .ok_or_else(|| ResolvePeerError::Unknown(peer.to_string()))?;
+    let fingerprint = known_peer.fingerprint();
+    Ok(known_peers
+        .iter()
+        .position(|known_peer| known_peer.fingerprint() == fingerprint)
+        .expect("matched peer is known"))
+}
+
+/// Whether name is the alias or nickname of a peer other than the one at index,
+/// which would make it ambiguous
+fn names_other_peer(known_peers: &[KnownPeer], index: usize, name: &str) -> bool {
+    known_peers
+        .iter()
+        .enumerate()
+        .filter(|(other_index, _)| *other_index != index)
+        .any(|(_, known_peer)| {
+            known_peer.name().eq_ignore_ascii_case(name)
+                || known_peer
+                    .peer_info
+                    .device_info
+                    .alias()
+                    .to_string()
+                    .eq_ignore_ascii_case(name)
+        })
+}
+
+fn list_peers(mut known_peers: Vec<KnownPeer>, format: OutputFormat) {
+    known_peers.sort_by_key(|known_peer| std::cmp::Reverse(known_peer.last_seen));
+    match format {
+        OutputFormat::Text => {
+            if known_peers.is_empty() {
+                println!("No peers known yet, run discover to find some");
+            }
+            let now = unix_now();
+            for known_peer in &known_peers {
+                println!("{}", text_line(known_peer, now));
+            }
+        }
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&known_peers).expect("fix this serialization")
+        ),
+        OutputFormat::Jsonl => {
+            for known_peer in &known_peers {
+                println!(
+                    "{}",
+                    serde_json::to_string(known_peer).expect("fix this serialization")
+                )
+            }
+        }
+    }
+}
+
+/// Name (alias too, if nicknamed), fingerprint prefix, url, when last seen and if trusted
+fn text_line(known_peer: &KnownPeer, now: u64) -> String {
+    let peer_info = &known_peer.peer_info;
+    let mut name = known_peer.name();
+    if known_peer.nickname.is_some() {
+        name.push_str(&format!(" ({})", peer_info.device_info.alias()));
+    }
+    let fingerprint = known_peer.fingerprint().as_ref();
+    let full_address = SocketAddr::new(peer_info.address, peer_info.port.dissolve());
+    format!(
+        "{name}\t{}\t{}://{full_address}\tseen {}{}",
+        &fingerprint[..fingerprint.len().min(8)],
+        peer_info.protocol.to_string().to_lowercase(),
+        ago(now.saturating_sub(known_peer.last_seen)),
+        if known_peer.trusted { "\ttrusted" } else { "" }
+    )
+}
+
+/// Rough human readable duration, ex `5m ago`
+fn ago(seconds: u64) -> String {
+    match seconds {
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{}m ago", seconds / 60),
+        3600..86400 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
@@ -0,0 +1,195 @@
+//! Pin protecting our servers (prepare-upload, prepare-download), with lockout of guessers.
+//! Every MAX_PIN_ATTEMPTS wrong pins from an address lock it out, for twice as long each time.
+//! Missing pins aren't counted, senders typically try without one first.
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use localsend_lib_types::{
+    api::Pin,
+    errors::{PrepareDownloadError, PrepareUploadError},
+};
+use subtle::ConstantTimeEq;
+
+/// Why a request didn't get past the pin
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PinRejection {
+    /// Pin missing or wrong (401)
+    Invalid,
+    /// Locked out after too many wrong pins (429)
+    TooManyAttempts,
+}
+
+impl From<PinRejection> for PrepareUploadError {
+    fn from(rejection: PinRejection) -> Self {
+        match rejection {
+            PinRejection::Invalid => PrepareUploadError::Http401,
+            PinRejection::TooManyAttempts => PrepareUploadError::Http429,
+        }
+    }
+}
+
+impl From<PinRejection> for PrepareDownloadError {
+    fn from(rejection: PinRejection) -> Self {
+        match rejection {
+            PinRejection::Invalid => PrepareDownloadError::Http401,
+            PinRejection::TooManyAttempts => PrepareDownloadError::Http429,
+        }
+    }
+}
+
+/// Checks pins given against ours (if any), keeping track of failures per address
+pub struct PinGuard {
+    pin: Option<String>,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+/// Wrong pins from an address since its last right one.
+/// Forgotten FAILURES_MEMORY after the last one, once any lockout is over.
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl PinGuard {
+    pub fn new(pin: Option<String>) -> Self {
+        PinGuard {
+            pin,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn check(&self, address: IpAddr, given_pin: Option<&Pin>) -> Result<(), PinRejection> {
+        self.check_at(address, given_pin, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        address: IpAddr,
+        given_pin: Option<&Pin>,
+        now: Instant,
+    ) -> Result<(), PinRejection> {
+        let Some(pin) = &self.pin else {
+            return Ok(());
+        };
+        let mut failures = self.failures.lock().expect("failures lock poisoned");
+        failures.retain(|_address, failures| {
+            now < failures.last_failure + FAILURES_MEMORY
+                || failures
+                    .locked_until
+                    .is_some_and(|locked_until| now < locked_until)
+        });
+        if let Some(Failures {
+            locked_until: Some(locked_until),
+            ..
+        }) = failures.get(&address)
+        {
+            if now < *locked_until {
+                return Err(PinRejection::TooManyAttempts);
+            }
+        }
+        let Some(given_pin) = given_pin else {
+            return Err(PinRejection::Invalid);
+        };
+        // Constant time, not to tell how much of a wrong pin was right
+        if bool::from(given_pin.as_ref().as_bytes().ct_eq(pin.as_bytes())) {
+            failures.remove(&address);
+            return Ok(());
+        }
+        let address_failures = failures.entry(address).or_insert(Failures {
+            count: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        address_failures.count += 1;
+        address_failures.last_failure = now;
+        println!("Wrong pin from {address}");
+        if address_failures.count.is_multiple_of(MAX_PIN_ATTEMPTS) {
+            let lockouts = address_failures.count / MAX_PIN_ATTEMPTS;
+            let cooldown = PIN_COOLDOWN
+                .saturating_mul(2u32.saturating_pow(lockouts - 1))
+                .min(MAX_PIN_COOLDOWN);
+            println!("Locking out {address} for {}s", cooldown.as_secs());
+            address_failures.locked_until = Some(now + cooldown);
+        }
+        Err(PinRejection::Invalid)
+    }
+}
+
+/// Pin to require: a random one if asked for (printed, for the user to pass on),
+/// else the one given by flag, else the one in config
+pub fn choose_pin(
+    random: bool,
+    flag_pin: Option<String>,
+    config_pin: Option<String>,
+) -> Option<String> {
+    if random {
+        let pin = random_pin();
+        println!("Pin: {pin}");
+        return Some(pin);
+    }
+    flag_pin.or(config_pin)
+}
+
+/// Random 6 digit pin
+fn random_pin() -> String {
+    format!("{:06}", uuid::Uuid::new_v4().as_u128() % 1_000_000)
+}
+
+/// Wrong pins allowed before a lockout
+const MAX_PIN_ATTEMPTS: u32 = 3;
+/// First lockout, doubling with every lockout after
+const PIN_COOLDOWN: Duration = Duration::from_secs(30);
+const MAX_PIN_COOLDOWN: Duration = Duration::from_secs(3600);
+/// How long wrong pins are held against an address, lockouts keep doubling meanwhile
+const FAILURES_MEMORY: Duration = MAX_PIN_COOLDOWN;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockout_doubles_after_every_few_failures() {
+        let guard = PinGuard::new(Some("1234".to_string()));
+        let address: IpAddr = "192.168.1.20".parse().unwrap();
+        let (right, wrong) = (Pin::new("1234".into()), Pin::new("0000".into()));
+        let start = Instant::now();
+        let check =
+            |pin, seconds| guard.check_at(address, pin, start + Duration::from_secs(seconds));
+        // Missing pins are refused, but don't count towards a lockout
+        for _ in 0..5 {
+            assert_eq!(check(None, 0), Err(PinRejection::Invalid));
+        }
+        assert_eq!(check(Some(&right), 0), Ok(()));
+        for _ in 0..3 {
+            assert_eq!(check(Some(&wrong), 0), Err(PinRejection::Invalid));
+        }
+        // Locked out for 30s, even with the right pin
+        assert_eq!(check(Some(&right), 29), Err(PinRejection::TooManyAttempts));
+        for _ in 0..3 {
+            assert_eq!(check(Some(&wrong), 30), Err(PinRejection::Invalid));
+        }
+        assert_eq!(check(Some(&right), 89), Err(PinRejection::TooManyAttempts));
+        assert_eq!(check(Some(&right), 90), Ok(()));
+        // Failures are forgotten a while after the lockout is over
+        for _ in 0..3 {
+            assert_eq!(check(Some(&wrong), 100), Err(PinRejection::Invalid));
+        }
+        assert_eq!(guard.failures.lock().unwrap().len(), 1);
+        assert_eq!(check(None, 3700), Err(PinRejection::Invalid));
+        assert!(guard.failures.lock().unwrap().is_empty());
+        // Others aren't affected
+        let other: IpAddr = "192.168.1.21".parse().unwrap();
+        assert_eq!(guard.check_at(other, Some(&right), start), Ok(()));
+        let prefix = Pin::new("123".into());
+        assert_eq!(
+            guard.check_at(other, Some(&prefix), start),
+            Err(PinRejection::Invalid)
+        );
+        assert_eq!(PinGuard::new(None).check(address, None), Ok(()));
+    }
+}
@@ -0,0 +1,280 @@
+//! Rules accepting or rejecting incoming sessions without asking, for unattended receivers.
+//! A rule matches an offer when all of its conditions hold (a rule without any matches all).
+//! The first matching rule decides. Without a match, the receiver's usual approval applies.
+//!
+//! Sender fingerprints and aliases are as the sender claims them in prepare-upload.
+//! Anyone can claim a fingerprint, so `fingerprints` and `trusted` only hold for a known peer
+//! sending from the address it was last seen at. They are as safe as addresses on the network.
+use std::net::IpAddr;
+
+use glob::{MatchOptions, Pattern};
+use localsend_lib_types::messages::common_fields::{DeviceInfo, FileInfo, Fingerprint};
+use serde::Deserialize;
+
+use crate::state::KnownPeer;
+
+/// What a rule does with the offers it matches
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Accept all files offered
+    Accept,
+    /// Reject session (403)
+    Reject,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    pub action: Action,
+    /// Sender fingerprint is one of these, and sender is a known peer at its last seen address
+    #[serde(default)]
+    pub fingerprints: Vec<Fingerprint>,
+    /// Sender alias matches one of these globs
+    #[serde(default)]
+    pub aliases: Vec<Glob>,
+    /// Sender is (or is not) trusted, see `peers trust`
+    pub trusted: Option<bool>,
+    /// Files offered add up to at most this many bytes
+    pub max_total_size: Option<u64>,
+    /// At most this many files are offered
+    pub max_files: Option<usize>,
+    /// Every file has a (mime) type matching one of these globs, ex `image/*`
+    #[serde(default)]
+    pub file_types: Vec<Glob>,
+    /// Every file name (path) matches one of these globs, ex `*.pdf`
+    #[serde(default)]
+    pub file_names: Vec<Glob>,
+}
+
+/// Shell style pattern (`*`, `?`, `[a-z]`), matched ignoring case
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Glob(Pattern);
+
+impl TryFrom<String> for Glob {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Pattern::new(&pattern)
+            .map(Glob)
+            .map_err(|error| format!("invalid pattern {pattern:?}: {error}"))
+    }
+}
+
+impl Glob {
+    fn matches(&self, text: &str) -> bool {
+        let options = MatchOptions {
+            case_sensitive: false,
+            ..MatchOptions::new()
+        };
+        self.0.matches_with(text, options)
+    }
+}
+
+/// Incoming session, as rules see it
+pub struct Offer<'a> {
+    pub sender: &'a DeviceInfo,
+    /// Sender's fingerprint is a known peer's, sending from the address it was last seen at
+    pub known: bool,
+    /// Sender is known, and trusted
+    pub trusted: bool,
+    pub files: Vec<&'a FileInfo>,
+}
+
+impl<'a> Offer<'a> {
+    /// Offer of files by sender at address, known (or not) among known peers
+    pub fn new(
+        sender: &'a DeviceInfo,
+        sender_address: IpAddr,
+        known_peers: &[KnownPeer],
+        files: Vec<&'a FileInfo>,
+    ) -> Self {
+        let known_peer = known_peers.iter().find(|known_peer| {
+            known_peer.fingerprint().matches(sender.fingerprint())
+                && known_peer.peer_info.address == sender_address
+        });
+        Offer {
+            sender,
+            known: known_peer.is_some(),
+            trusted: known_peer.is_some_and(|known_peer| known_peer.trusted),
+            files,
+        }
+    }
+}
+
+impl Policy {
+    pub fn matches(&self, offer: &Offer) -> bool {
+        let sender = offer.sender;
+        let total_size: u64 = offer.files.iter().map(|file_info| file_info.size()).sum();
+        (self.fingerprints.is_empty()
+            || offer.known
+                && self
+                    .fingerprints
+                    .iter()
+                    .any(|fingerprint| fingerprint.matches(sender.fingerprint())))
+            && any_matches(&self.aliases, &sender.alias().to_string())
+            && self.trusted.is_none_or(|trusted| trusted == offer.trusted)
+            && self
+                .max_total_size
+                .is_none_or(|max_total_size| total_size <= max_total_size)
+            && self
+                .max_files
+                .is_none_or(|max_files| offer.files.len() <= max_files)
+            && offer.files.iter().all(|file_info| {
+                any_matches(&self.file_types, file_info.file_type())
+                    && any_matches(&self.file_names, file_info.file_name())
+            })
+    }
+}
+
+/// Whether text matches one of globs, or there are none to match
+fn any_matches(globs: &[Glob], text: &str) -> bool {
+    globs.is_empty() || globs.iter().any(|glob| glob.matches(text))
+}
+
+/// First rule matching offer, with its number (counting from 1), if any
+pub fn first_match<'a>(policies: &'a [Policy], offer: &Offer) -> Option<(usize, &'a Policy)> {
+    policies
+        .iter()
+        .enumerate()
+        .find(|(_index, policy)| policy.matches(offer))
+        .map(|(index, policy)| (index + 1, policy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, discover::PeerInfo};
+    use localsend_lib_types::messages::common_fields::{DeviceType, Protocol, Version};
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let config: Config = toml::from_str(
+            r#"
+            [[policy]]
+            action = "accept"
+            trusted = true
+
+            [[policy]]
+            action = "accept"
+            aliases = ["build *"]
+            max_total_size = 100
+            file_names = ["*.tar.gz"]
+
+            [[policy]]
+            action = "reject"
+            "#,
+        )
+        .unwrap();
+        let sender = DeviceInfo::new(
+            "Build Box".to_string().into(),
+            None,
+            DeviceType::Headless,
+            "ab12".to_string().into(),
+        );
+        let file = |name: &str, size| {
+            FileInfo::new(
+                name.to_string().into(),
+                name.to_string(),
+                size,
+                "application/gzip".to_string(),
+                None,
+                None,
+                None,
+            )
+        };
+        let (small, large, other) = (
+            file("out/app.tar.gz", 60),
+            file("out/app.tar.gz", 200),
+            file("out/app.zip", 10),
+        );
+        let outcome = |trusted, files: Vec<&FileInfo>| {
+            let offer = Offer {
+                sender: &sender,
+                known: trusted,
+                trusted,
+                files,
+            };
+            first_match(&config.policy, &offer).map(|(number, policy)| (number, policy.action))
+        };
+        assert_eq!(outcome(true, vec![&large]), Some((1, Action::Accept)));
+        assert_eq!(outcome(false, vec![&small]), Some((2, Action::Accept)));
+        assert_eq!(outcome(false, vec![&large]), Some((3, Action::Reject)));
+        assert_eq!(
+            outcome(false, vec![&small, &other]),
+            Some((3, Action::Reject))
+        );
+        let empty = Offer {
+            sender: &sender,
+            known: false,
+            trusted: false,
+            files: vec![],
+        };
+        assert!(first_match(&[], &empty).is_none());
+    }
+
+    #[test]
+    fn spoofed_fingerprint_not_accepted() {
+        let config: Config = toml::from_str(
+            r#"
+            [[policy]]
+            action = "accept"
+            trusted = true
+
+            [[policy]]
+            action = "accept"
+            fingerprints = ["AB12"]
+            "#,
+        )
+        .unwrap();
+        let sender = DeviceInfo::new(
+            "Build Box".to_string().into(),
+            None,
+            DeviceType::Headless,
+            "ab12".to_string().into(),
+        );
+        let known_peer = KnownPeer {
+            peer_info: PeerInfo {
+                device_info: sender.clone(),
+                address: "192.168.1.2".parse().unwrap(),
+                port: 53317.into(),
+                protocol: Protocol::Https,
+                version: Version::default(),
+                download_mode: false,
+                interface: None,
+                scope_id: None,
+            },
+            nickname: None,
+            trusted: true,
+            first_seen: 0,
+            last_seen: 0,
+        };
+        let outcome = |sender_address: &str| {
+            let offer = Offer::new(
+                &sender,
+                sender_address.parse().unwrap(),
+                std::slice::from_ref(&known_peer),
+                vec![],
+            );
+            first_match(&config.policy, &offer).map(|(number, _policy)| number)
+        };
+        assert_eq!(outcome("192.168.1.2"), Some(1));
+        // Same fingerprint claimed from elsewhere
+        assert_eq!(outcome("192.168.1.66"), None);
+        let untrusted = KnownPeer {
+            trusted: false,
+            ..known_peer.clone()
+        };
+        let offer = Offer::new(
+            &sender,
+            "192.168.1.2".parse().unwrap(),
+            &[untrusted],
+            vec![],
+        );
+        assert_eq!(
+            first_match(&config.policy, &offer).map(|(number, _)| number),
+            Some(2)
+        );
+    }
+}
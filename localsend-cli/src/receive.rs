@@ -0,0 +1,676 @@
+use std::{
+    fs::FileTimes,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{rejection::QueryRejection, ConnectInfo, Query, State},
+    routing::post,
+    Json, Router,
+};
+use futures_util::StreamExt;
+use localsend_lib_types::{
+    api::{CancelParams, PrepareUploadParams, UploadParams},
+    errors::{PrepareUploadError, UploadError},
+    messages::{
+        common_fields::{Protocol, SessionId},
+        discover::MulticastCommon,
+        upload::{PrepareUploadRequest, PrepareUploadResponse},
+    },
+};
+use std::io::Error as IoError;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader, Lines},
+    sync::{broadcast::error::RecvError, oneshot},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    args::{OutputFormat, ReceiveArgs},
+    config::load_config,
+    discover::{register_routes, Discovery},
+    events::{Event, Events},
+    pin::{choose_pin, PinGuard},
+    policy::{first_match, Action, Offer, Policy},
+    server::{serve, ApiError},
+    session::{Decision, IncomingSession, SessionManager},
+    state::{load_known_peers, load_state},
+    utils::{ask_confirm_from, dbgr, file_times, numbered_path, sanitize_relative_path},
+};
+
+/// Receive files pushed to us by other localsend devices (upload api).
+/// Refused while a daemon runs, it receives files already.
+pub fn receive(receive_args: ReceiveArgs) {
+    #[cfg(unix)]
+    if crate::control::ControlClient::connect().is_some() {
+        println!("A daemon is running and receives files already, stop it to receive here");
+        return;
+    }
+    let destination = receive_args.destination();
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(error) => {
+            println!("{error}");
+            return;
+        }
+    };
+    let pin = choose_pin(
+        receive_args.random_pin(),
+        receive_args.pin().clone(),
+        config.pin,
+    );
+    let events = Events::new();
+    let approval = if receive_args.confirm() {
+        Approval::Pending
+    } else {
+        Approval::Auto
+    };
+    let receiver = Arc::new(
+        Receiver::new(destination.to_path_buf())
+            .with_approval(approval)
+            .with_policies(config.policy)
+            .with_pin(pin)
+            .with_events(events.clone()),
+    );
+    let rt = tokio::runtime::Runtime::new().inspect_err(dbgr).unwrap();
+    if approval == Approval::Pending {
+        rt.spawn(confirm_sessions(receiver.clone(), events));
+    }
+    println!(
+        "Waiting for files on port {}, saving to {destination:?}",
+        receive_args.port()
+    );
+    let protocol = if receive_args.http() {
+        Protocol::Http
+    } else {
+        Protocol::Https
+    };
+    // Senders given our ip address register first, to learn who we are. They aren't listed.
+    let announcement = MulticastCommon::new(
+        load_state().device_info,
+        receive_args.port().into(),
+        protocol,
+        Some(false),
+    );
+    let discovery = Arc::new(Discovery::new(announcement, OutputFormat::Json));
+    let routes = upload_routes(receiver).merge(register_routes(discovery));
+    rt.block_on(serve(
+        routes,
+        receive_args.port(),
+        protocol,
+        CancellationToken::new(),
+    ))
+    .inspect_err(dbgr)
+    .unwrap();
+}
+
+/// Ask the user about each pending session as it comes in, and decide on it accordingly.
+/// Questions left unanswered once the session stopped waiting (APPROVAL_TIMEOUT) are dropped.
+async fn confirm_sessions(receiver: Arc<Receiver>, events: Events) {
+    let mut events = events.subscribe();
+    let mut answers = AsyncBufReader::new(tokio::io::stdin()).lines();
+    loop {
+        let session = match events.recv().await {
+            Ok(Event::SessionIncoming { session }) if session.pending => session,
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        let session_id = session.session_id.clone();
+        let decision =
+            tokio::time::timeout(APPROVAL_TIMEOUT, ask_decision(&session, &mut answers)).await;
+        let decision = match decision {
+            Ok(Ok(decision)) => decision,
+            // No one to ask
+            Ok(Err(_)) => Decision::Reject,
+            Err(_elapsed) => {
+                println!("Session {session_id} waited too long, not asking anymore");
+                continue;
+            }
+        };
+        if !receiver.decide(&session_id, decision) {
+            println!("Session {session_id} is no longer pending");
+        }
+    }
+}
+
+/// Accept all files of session, reject it, or accept those picked one by one
+async fn ask_decision<R>(
+    session: &IncomingSession,
+    answers: &mut Lines<R>,
+) -> Result<Decision, IoError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let files_count = session.files.len();
+    let query = format!(
+        "Accept {files_count} files from {} @{}?",
+        session.sender, session.sender_address
+    );
+    if ask_confirm_from(answers, &query, None).await? {
+        return Ok(Decision::Accept(None));
+    }
+    if files_count < 2
+        || !ask_confirm_from(answers, "Pick some of them instead?", Some(false)).await?
+    {
+        return Ok(Decision::Reject);
+    }
+    let mut accepted = Vec::new();
+    for file_info in &session.files {
+        let query = format!("Accept {} ({}b)?", file_info.file_name(), file_info.size());
+        if ask_confirm_from(answers, &query, None).await? {
+            accepted.push(file_info.id().clone());
+        }
+    }
+    if accepted.is_empty() {
+        return Ok(Decision::Reject);
+    }
+    Ok(Decision::Accept(Some(accepted)))
+}
+
+/// Routes of the upload api (prepare-upload, upload, cancel)
+pub fn upload_routes(receiver: Arc<Receiver>) -> Router {
+    Router::new()
+        .route("/api/localsend/v2/prepare-upload", post(prepare_upload))
+        .route("/api/localsend/v2/upload", post(upload))
+        .route("/api/localsend/v2/cancel", post(cancel))
+        .with_state(receiver)
+}
+
+/// How incoming sessions get accepted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Approval {
+    /// Accept all files offered, right away
+    Auto,
+    /// Hold sessions pending until decided on (ex through the control api),
+    /// rejecting them if not decided within APPROVAL_TIMEOUT
+    Pending,
+}
+
+/// How long a pending session may wait to be decided on.
+/// Senders typically give up on prepare-upload after a while too.
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Upload server state.
+/// As per protocol, only one session is served at a time.
+pub struct Receiver {
+    destination: PathBuf,
+    approval: Approval,
+    /// Decide before approval, see [crate::policy]
+    policies: Vec<Policy>,
+    pin_guard: PinGuard,
+    events: Events,
+    sessions: Mutex<SessionManager>,
+}
+
+impl Receiver {
+    pub fn new(destination: PathBuf) -> Self {
+        Receiver {
+            destination,
+            approval: Approval::Auto,
+            policies: Vec::new(),
+            pin_guard: PinGuard::new(None),
+            events: Events::new(),
+            sessions: Mutex::new(SessionManager::default()),
+        }
+    }
+
+    pub fn with_approval(self, approval: Approval) -> Self {
+        Receiver { approval, ..self }
+    }
+
+    pub fn with_events(self, events: Events) -> Self {
+        Receiver { events, ..self }
+    }
+
+    pub fn with_policies(self, policies: Vec<Policy>) -> Self {
+        Receiver { policies, ..self }
+    }
+
+    /// Require senders to provide pin
+    pub fn with_pin(self, pin: Option<String>) -> Self {
+        Receiver {
+            pin_guard: PinGuard::new(pin),
+            ..self
+        }
+    }
+
+    /// Action of the first policy matching the offer, with its number
+    fn policy_action(
+        &self,
+        request: &PrepareUploadRequest,
+        sender_address: IpAddr,
+    ) -> Option<(usize, Action)> {
+        if self.policies.is_empty() {
+            return None;
+        }
+        let offer = Offer::new(
+            request.info().device_info(),
+            sender_address,
+            &load_known_peers(),
+            request.files().as_ref().values().collect(),
+        );
+        first_match(&self.policies, &offer).map(|(number, policy)| (number, policy.action))
+    }
+
+    /// Session manager, once idle sessions are expired
+    fn lock_sessions(&self) -> MutexGuard<'_, SessionManager> {
+        let mut sessions = self.sessions.lock().expect("sessions lock poisoned");
+        if let Some(session_id) = sessions.expire_idle(Instant::now()) {
+            println!("Session {session_id} expired");
+            self.events.emit(Event::SessionExpired { session_id });
+        }
+        sessions
+    }
+
+    /// Current session, if any
+    pub fn sessions(&self) -> Vec<IncomingSession> {
+        self.lock_sessions().current().into_iter().collect()
+    }
+
+    /// Decide on pending session. False if there is no such session pending.
+    pub fn decide(&self, session_id: &SessionId, decision: Decision) -> bool {
+        self.lock_sessions().decide(session_id, decision)
+    }
+
+    /// Cancel current session, if it has given id (or none is given).
+    /// False if there is no such session.
+    pub fn cancel_session(&self, session_id: Option<&SessionId>) -> bool {
+        let Some(session_id) = self.lock_sessions().cancel(session_id) else {
+            return false;
+        };
+        self.events.emit(Event::SessionCancelled { session_id });
+        true
+    }
+}
+
+async fn prepare_upload(
+    State(receiver): State<Arc<Receiver>>,
+    ConnectInfo(sender_address): ConnectInfo<SocketAddr>,
+    params: Result<Query<PrepareUploadParams>, QueryRejection>,
+    body: Bytes,
+) -> Result<Json<PrepareUploadResponse>, ApiError> {
+    let sender_address = sender_address.ip().to_canonical();
+    let given_pin = params.as_ref().ok().map(|Query(params)| params.pin());
+    receiver
+        .pin_guard
+        .check(sender_address, given_pin)
+        .map_err(PrepareUploadError::from)?;
+    let request: PrepareUploadRequest =
+        serde_json::from_slice(&body).map_err(|_| PrepareUploadError::Http400)?;
+    let approval = match receiver.policy_action(&request, sender_address) {
+        Some((number, Action::Reject)) => {
+            println!("Rejected by policy {number}");
+            return Err(PrepareUploadError::Http403.into());
+        }
+        Some((number, Action::Accept)) => {
+            println!("Accepted by policy {number}");
+            Approval::Auto
+        }
+        None => receiver.approval,
+    };
+    let (decide, decision) = match approval {
+        Approval::Auto => (None, None),
+        Approval::Pending => {
+            let (decide, decision) = oneshot::channel();
+            (Some(decide), Some(decision))
+        }
+    };
+    let files_map = request.files().as_ref();
+    let session = receiver.lock_sessions().begin(
+        request.info().device_info().clone(),
+        sender_address,
+        files_map.values().cloned(),
+        decide,
+        Instant::now(),
+    )?;
+    let session_id = session.session_id.clone();
+    println!(
+        "Receiving files from {} @{sender_address}",
+        request.info().device_info(),
+    );
+    for file_info in files_map.values() {
+        println!(
+            "{}\t{}b\t{}",
+            file_info.file_type(),
+            file_info.size(),
+            file_info.file_name()
+        )
+    }
+    receiver.events.emit(Event::SessionIncoming { session });
+    let files = match decision {
+        None => None,
+        Some(decision) => {
+            println!("Session {session_id} awaits acceptance");
+            match tokio::time::timeout(APPROVAL_TIMEOUT, decision).await {
+                Ok(Ok(Decision::Accept(files))) => files,
+                // Rejected, timed out, or cancelled meanwhile
+                _ => {
+                    println!("Session {session_id} rejected");
+                    receiver.lock_sessions().cancel(Some(&session_id));
+                    receiver.events.emit(Event::SessionRejected { session_id });
+                    return Err(PrepareUploadError::Http403.into());
+                }
+            }
+        }
+    };
+    let accepted = receiver
+        .lock_sessions()
+        .accept(&session_id, files, Instant::now());
+    let Some(tokens_map) = accepted else {
+        return Err(PrepareUploadError::Http204.into());
+    };
+    receiver.events.emit(Event::SessionAccepted {
+        session_id: session_id.clone(),
+    });
+    Ok(Json(PrepareUploadResponse::new(session_id, tokens_map)))
+}
+
+async fn upload(
+    State(receiver): State<Arc<Receiver>>,
+    ConnectInfo(sender_address): ConnectInfo<SocketAddr>,
+    params: Result<Query<UploadParams>, QueryRejection>,
+    body: Body,
+) -> Result<(), ApiError> {
+    let Ok(Query(params)) = params else {
+        return Err(UploadError::Http400.into());
+    };
+    let (session_id, file_id) = (params.session_id(), params.file_id());
+    let file_info = receiver.lock_sessions().start_upload(
+        &params,
+        sender_address.ip().to_canonical(),
+        Instant::now(),
+    )?;
+    let file_path = receiver
+        .destination
+        .join(sanitize_relative_path(file_info.file_name()));
+    let written = write_body(&file_path, body, file_times(&file_info), |bytes| {
+        receiver
+            .lock_sessions()
+            .progress(session_id, file_id, bytes, Instant::now())
+    })
+    .await;
+    let complete = receiver.lock_sessions().finish_upload(
+        session_id,
+        file_id,
+        written.is_ok(),
+        Instant::now(),
+    );
+    let Some(complete) = complete else {
+        // Cancelled or expired meanwhile, what we got is of no use
+        if let Ok(written_path) = &written {
+            println!("Session {session_id} ended, removing {:?}", written_path);
+            let _ = tokio::fs::remove_file(written_path).await;
+        }
+        receiver.events.emit(Event::FileReceiveFailed {
+            session_id: session_id.clone(),
+            file_name: file_info.file_name().to_string(),
+            reason: "session ended during upload".to_string(),
+        });
+        return Err(UploadError::Http403.into());
+    };
+    if let Err(error) = written {
+        dbgr(&error);
+        receiver.events.emit(Event::FileReceiveFailed {
+            session_id: params.session_id().clone(),
+            file_name: file_info.file_name().to_string(),
+            reason: error.to_string(),
+        });
+        return Err(UploadError::Http500.into());
+    }
+    receiver.events.emit(Event::FileReceived {
+        session_id: session_id.clone(),
+        file_name: file_info.file_name().to_string(),
+    });
+    if complete {
+        println!("Session {session_id} complete");
+        receiver.events.emit(Event::SessionFinished {
+            session_id: session_id.clone(),
+        });
+    }
+    Ok(())
+}
+
+async fn cancel(
+    State(receiver): State<Arc<Receiver>>,
+    params: Result<Query<CancelParams>, QueryRejection>,
+) {
+    // Some senders don't pass the session id. Then cancel whatever is ongoing.
+    let session_id = params.ok().map(|Query(params)| params.session_id().clone());
+    if receiver.cancel_session(session_id.as_ref()) {
+        println!("Session cancelled by sender");
+    }
+}
+
+/// Stream request body into a new file, creating parent directories as needed.
+/// Existing files are never overwritten, a number is added to the name instead (see [numbered_path]).
+/// Reports the size of every chunk written. Once done, file is given the times passed.
+/// If the body can't be written whole, the file is removed, never to look complete.
+/// Returns the path written to.
+async fn write_body(
+    file_path: &Path,
+    body: Body,
+    file_times: FileTimes,
+    on_chunk: impl FnMut(u64),
+) -> Result<PathBuf, IoError> {
+    if let Some(parent_dir) = file_path.parent() {
+        tokio::fs::create_dir_all(parent_dir).await?;
+    };
+    let (file_path, mut open_file) = create_new_file(file_path).await?;
+    println!("writing {:?}", file_path);
+    if let Err(error) = copy_body(body, &mut open_file, on_chunk).await {
+        drop(open_file);
+        let _ = tokio::fs::remove_file(&file_path).await;
+        return Err(error);
+    }
+    // Contents are what matter, times are nice to have
+    if let Err(error) = open_file.into_std().await.set_times(file_times) {
+        println!("Could not set times of {:?}: {error}", file_path);
+    }
+    Ok(file_path)
+}
+
+async fn copy_body(
+    body: Body,
+    open_file: &mut tokio::fs::File,
+    mut on_chunk: impl FnMut(u64),
+) -> Result<(), IoError> {
+    let mut body_stream = body.into_data_stream();
+    while let Some(chunk) = body_stream.next().await {
+        let chunk = chunk.map_err(IoError::other)?;
+        open_file.write_all(&chunk).await?;
+        on_chunk(chunk.len() as u64);
+    }
+    open_file.flush().await
+}
+
+/// Create file at path, or at the first numbered path not taken
+async fn create_new_file(file_path: &Path) -> Result<(PathBuf, tokio::fs::File), IoError> {
+    let mut candidate = file_path.to_path_buf();
+    for number in 1.. {
+        let created = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+            .await;
+        match created {
+            Ok(open_file) => return Ok((candidate, open_file)),
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {
+                candidate = numbered_path(file_path, number);
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    unreachable!("some numbered path is free")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv6Addr, TcpListener};
+
+    use localsend_client::LocalSendClient;
+    use localsend_lib_types::messages::{
+        common_fields::{DeviceInfo, FileInfo, Protocol},
+        upload::PrepareUploadDeviceInfo,
+    };
+
+    use super::*;
+    use crate::{
+        server::serve_on,
+        utils::{collect_files, peer_url},
+    };
+
+    #[tokio::test]
+    async fn upload_over_ipv6_loopback() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let source_file = source.path().join("hello.txt");
+        std::fs::write(&source_file, "hello over ipv6").unwrap();
+        let listener = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let receiver = Arc::new(Receiver::new(destination.path().to_path_buf()));
+        tokio::spawn(serve_on(
+            listener,
+            upload_routes(receiver),
+            Protocol::Http,
+            CancellationToken::new(),
+        ));
+
+        let local_files = collect_files(&[source_file]).unwrap();
+        let request = PrepareUploadRequest::from_files(
+            PrepareUploadDeviceInfo::from(DeviceInfo::default())
+                .with_port(port)
+                .with_protocol(Protocol::Http),
+            local_files
+                .into_values()
+                .map(|(file_info, _path)| file_info),
+        );
+        let base_url = peer_url(Protocol::Http, Ipv6Addr::LOCALHOST.into(), port);
+        assert_eq!(base_url.host_str(), Some("[::1]"));
+        let client = LocalSendClient::new(base_url).unwrap();
+        let response = client.prepare_upload(&request, None).await.unwrap();
+        for (file_id, token) in response.files().iter() {
+            client
+                .upload(
+                    response.session_id(),
+                    file_id,
+                    token,
+                    &b"hello over ipv6"[..],
+                )
+                .await
+                .unwrap();
+        }
+        let received = std::fs::read_to_string(destination.path().join("hello.txt")).unwrap();
+        assert_eq!(received, "hello over ipv6");
+
+        // Same name again gets a numbered file, the first one is kept
+        let response = client.prepare_upload(&request, None).await.unwrap();
+        for (file_id, token) in response.files().iter() {
+            client
+                .upload(response.session_id(), file_id, token, &b"hello again"[..])
+                .await
+                .unwrap();
+        }
+        let received = std::fs::read_to_string(destination.path().join("hello.txt")).unwrap();
+        assert_eq!(received, "hello over ipv6");
+        let received = std::fs::read_to_string(destination.path().join("hello (1).txt")).unwrap();
+        assert_eq!(received, "hello again");
+
+        // Nothing to transfer (204) isn't a failure
+        let nothing = PrepareUploadRequest::from_files(DeviceInfo::default(), []);
+        let response = client.prepare_upload(&nothing, None).await.unwrap();
+        assert!(response.files().is_empty());
+    }
+
+    /// Upload half a file, then either cancel the session or drop the connection.
+    /// Either way the file fails, and nothing of it is left.
+    async fn interrupted_upload(cancel: bool) {
+        let destination = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let events = Events::new();
+        let receiver =
+            Arc::new(Receiver::new(destination.path().to_path_buf()).with_events(events.clone()));
+        tokio::spawn(serve_on(
+            listener,
+            upload_routes(receiver),
+            Protocol::Http,
+            CancellationToken::new(),
+        ));
+        let file_info = FileInfo::new(
+            "1".to_string().into(),
+            "big.bin".to_string(),
+            8,
+            "application/octet-stream".to_string(),
+            None,
+            None,
+            None,
+        );
+        let request = PrepareUploadRequest::from_files(DeviceInfo::default(), [file_info]);
+        let client =
+            LocalSendClient::new(peer_url(Protocol::Http, Ipv6Addr::LOCALHOST.into(), port))
+                .unwrap();
+        let response = client.prepare_upload(&request, None).await.unwrap();
+        let (file_id, token) = response.files().iter().next().unwrap();
+        let (mut body_writer, body_reader) = tokio::io::duplex(64);
+        let mut events = events.subscribe();
+        let upload = tokio::spawn({
+            let client = client.clone();
+            let (session_id, file_id, token) = (
+                response.session_id().clone(),
+                file_id.clone(),
+                token.clone(),
+            );
+            async move {
+                client
+                    .upload(&session_id, &file_id, &token, body_reader)
+                    .await
+            }
+        });
+        body_writer.write_all(b"half").await.unwrap();
+        let partial_file = destination.path().join("big.bin");
+        while !partial_file.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        if cancel {
+            client.cancel(response.session_id()).await.unwrap();
+            body_writer.write_all(b"rest").await.unwrap();
+            drop(body_writer);
+            let refused = upload.await.unwrap().unwrap_err();
+            assert!(
+                matches!(
+                    refused,
+                    localsend_client::errors::ClientError::Upload(UploadError::Http403, _)
+                ),
+                "{refused}"
+            );
+        } else {
+            // Sender goes away mid-file
+            upload.abort();
+        }
+        loop {
+            match events.recv().await.unwrap() {
+                Event::FileReceiveFailed { file_name, .. } => {
+                    assert_eq!(file_name, "big.bin");
+                    break;
+                }
+                Event::FileReceived { .. } => panic!("interrupted upload was received"),
+                _ => {}
+            }
+        }
+        assert!(!partial_file.exists());
+    }
+
+    #[tokio::test]
+    async fn upload_refused_once_cancelled() {
+        interrupted_upload(true).await;
+    }
+
+    #[tokio::test]
+    async fn upload_broken_off_leaves_nothing() {
+        interrupted_upload(false).await;
+    }
+}
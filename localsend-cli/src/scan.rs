@@ -0,0 +1,186 @@
+//! Discovery by sweeping subnets with register requests, as the official apps do
+//! on networks that drop multicast.
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use futures_util::{stream, StreamExt};
+use if_addrs::{get_if_addrs, IfAddr};
+use localsend_client::{errors::ClientError, LocalSendClient};
+use localsend_lib_types::messages::common_fields::Protocol;
+
+use crate::{
+    constants::{FOX_USER_AGENT, LOCALSEND_PORT},
+    discover::{Discovery, PeerInfo},
+    multicast::{interface_of, MulticastScope},
+    utils::peer_url,
+};
+
+/// Ipv4 subnet, ex `192.168.1.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Subnet {
+    network: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Subnet {
+    /// Subnet of given address, host bits are dropped
+    pub fn new(address: Ipv4Addr, prefix_len: u8) -> Self {
+        let prefix_len = prefix_len.min(32);
+        let netmask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+        Ipv4Subnet {
+            network: Ipv4Addr::from(u32::from(address) & netmask),
+            prefix_len,
+        }
+    }
+
+    /// Addresses of hosts in subnet, leaving out the network and broadcast addresses
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let network = u32::from(self.network) as u64;
+        let size = 1u64 << (32 - self.prefix_len);
+        let (first, last) = if self.prefix_len >= 31 {
+            (network, network + size - 1)
+        } else {
+            (network + 1, network + size - 2)
+        };
+        (first..=last).map(|address| Ipv4Addr::from(address as u32))
+    }
+}
+
+impl fmt::Display for Ipv4Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl FromStr for Ipv4Subnet {
+    type Err = String;
+
+    fn from_str(subnet: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid subnet {subnet}, expected one like 192.168.1.0/24");
+        let (address, prefix_len) = subnet.split_once('/').ok_or_else(invalid)?;
+        let address: Ipv4Addr = address.parse().map_err(|_| invalid())?;
+        let prefix_len: u8 = prefix_len.parse().map_err(|_| invalid())?;
+        if prefix_len > 32 {
+            return Err(invalid());
+        }
+        if prefix_len < MIN_SCAN_PREFIX_LEN {
+            return Err(format!(
+                "subnet {subnet} is too large to scan, use /{MIN_SCAN_PREFIX_LEN} or smaller"
+            ));
+        }
+        Ok(Ipv4Subnet::new(address, prefix_len))
+    }
+}
+
+/// Subnets of our ipv4 interfaces in scope, loopback left out.
+/// Larger subnets are narrowed to the /24 around our address, as the official apps do.
+pub fn local_subnets(scope: &MulticastScope) -> Vec<Ipv4Subnet> {
+    let interfaces = match get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(error) => {
+            eprintln!("Could not list network interfaces to scan: {error}");
+            return Vec::new();
+        }
+    };
+    let mut subnets = Vec::new();
+    for interface in interfaces {
+        if interface.is_loopback() || !scope.selects(&interface) {
+            continue;
+        }
+        if let IfAddr::V4(address) = &interface.addr {
+            let subnet = Ipv4Subnet::new(address.ip, address.prefixlen.max(24));
+            if !subnets.contains(&subnet) {
+                subnets.push(subnet);
+            }
+        }
+    }
+    subnets
+}
+
+/// Register with every host of subnets (at the default port), adding those that reply as peers.
+/// At most SCAN_CONCURRENCY requests are in flight, each given up after SCAN_TIMEOUT.
+/// Https is tried first, then plain http for hosts that fail it without timing out.
+pub async fn scan_subnets(discovery: Arc<Discovery>, subnets: Vec<Ipv4Subnet>) {
+    let mut hosts: Vec<Ipv4Addr> = subnets.iter().flat_map(Ipv4Subnet::hosts).collect();
+    hosts.sort_unstable();
+    hosts.dedup();
+    for subnet in &subnets {
+        eprintln!("Scanning {subnet}");
+    }
+    stream::iter(hosts)
+        .map(|host| register_with(discovery.clone(), host))
+        .buffer_unordered(SCAN_CONCURRENCY)
+        .collect::<()>()
+        .await;
+}
+
+async fn register_with(discovery: Arc<Discovery>, host: Ipv4Addr) {
+    let address = IpAddr::V4(host);
+    let legacy_register = discovery.legacy_register();
+    for protocol in [Protocol::Https, Protocol::Http] {
+        let Ok(client) = LocalSendClient::new(peer_url(protocol, address, LOCALSEND_PORT))
+            .and_then(|client| client.with_user_agent(FOX_USER_AGENT))
+        else {
+            return;
+        };
+        let legacy_register_response =
+            match tokio::time::timeout(SCAN_TIMEOUT, client.register(&legacy_register)).await {
+                Ok(Ok(legacy_register_response)) => legacy_register_response,
+                // Certificate not that of the device replying, don't list it
+                Ok(Err(ClientError::FingerprintMismatch { .. })) => return,
+                // Refused, or not (plain http) tls
+                Ok(Err(_)) => continue,
+                // Nobody there
+                Err(_elapsed) => return,
+            };
+        if !discovery.is_self(legacy_register_response.device_info().fingerprint()) {
+            discovery.add_peer(PeerInfo {
+                device_info: legacy_register_response.device_info().clone(),
+                address,
+                port: LOCALSEND_PORT.into(),
+                protocol,
+                version: *legacy_register_response.version(),
+                download_mode: legacy_register_response.download().unwrap_or(false),
+                interface: interface_of(address),
+                scope_id: None,
+            });
+        }
+        return;
+    }
+}
+
+/// Largest subnet we agree to scan (/16, ~65k hosts)
+const MIN_SCAN_PREFIX_LEN: u8 = 16;
+
+/// How many hosts to register with at once
+const SCAN_CONCURRENCY: usize = 64;
+
+/// How long to wait on a host, before deciding no peer is there
+const SCAN_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_subnet_and_list_hosts() {
+        let subnet: Ipv4Subnet = "192.168.1.77/24".parse().unwrap();
+        assert_eq!(subnet.to_string(), "192.168.1.0/24");
+        let hosts: Vec<Ipv4Addr> = subnet.hosts().collect();
+        assert_eq!(hosts.len(), 254);
+        assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(hosts[253], Ipv4Addr::new(192, 168, 1, 254));
+
+        let point_to_point: Ipv4Subnet = "10.0.0.1/31".parse().unwrap();
+        assert_eq!(point_to_point.hosts().count(), 2);
+
+        assert!("10.0.0.0/8".parse::<Ipv4Subnet>().is_err());
+        assert!("10.0.0.0".parse::<Ipv4Subnet>().is_err());
+        assert!("10.0.0.0/33".parse::<Ipv4Subnet>().is_err());
+    }
+}
@@ -0,0 +1,239 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::args::SendArgs;
+use crate::discover::{resolve_peer, standalone_register};
+use crate::state::load_state;
+use crate::utils::{collect_files, dbgr, retry_too_many_requests};
+use compounderr::compose_errors as funsie_errors;
+use localsend_client::{errors::ClientError, LocalSendClient};
+use localsend_lib_types::messages::common_fields::{
+    FileId, FileInfo, FileUploadToken, Protocol, SessionId,
+};
+use localsend_lib_types::messages::upload::{
+    PrepareUploadDeviceInfo, PrepareUploadRequest, PrepareUploadResponse,
+};
+use std::io::Error as IoError;
+
+/// Send files (and directories) to given receiver, using pin if required.
+/// If a daemon is running, it sends them for us.
+pub fn send(send_args: SendArgs) {
+    #[cfg(unix)]
+    if let Some(control_client) = crate::control::ControlClient::connect() {
+        send_through_daemon(control_client, &send_args);
+        return;
+    }
+    let protocol = if send_args.http() {
+        Protocol::Http
+    } else {
+        Protocol::Https
+    };
+    let peer_address = match resolve_peer(
+        send_args.receiver(),
+        send_args.port(),
+        protocol,
+        *send_args.protocol_version(),
+    ) {
+        Ok(peer_address) => peer_address,
+        Err(error) => {
+            println!("{error}");
+            return;
+        }
+    };
+    let local_files = match collect_files(send_args.paths()) {
+        Ok(local_files) => local_files,
+        Err(error) => {
+            println!("Could not read files to send: {error}");
+            return;
+        }
+    };
+    if local_files.is_empty() {
+        println!("Nothing to send");
+        return;
+    }
+    for (file_info, _path) in local_files.values() {
+        println!(
+            "{}\t{}b\t{}",
+            file_info.file_type(),
+            file_info.size(),
+            file_info.file_name()
+        )
+    }
+    // Nothing of ours listens while sending
+    let prepare_upload_request = prepare_upload_request(&local_files, None);
+    let client = peer_address.client().inspect_err(dbgr).unwrap();
+    let rt = tokio::runtime::Runtime::new().inspect_err(dbgr).unwrap();
+    if let Err(error) = rt.block_on(peer_address.identify(&client, &standalone_register())) {
+        println!("Could not identify receiver: {error}");
+        return;
+    }
+    println!("Waiting for receiver to accept");
+    let pin = send_args.pin().as_deref();
+    let prepare_upload_response = match rt.block_on(retry_too_many_requests(|| {
+        client.prepare_upload(&prepare_upload_request, pin)
+    })) {
+        Ok(prepare_upload_response) => prepare_upload_response,
+        Err(error) => {
+            println!("Receiver did not accept: {error}");
+            if error.is_pin_required() && pin.is_none() {
+                println!("Pass the pin shown by receiver using --pin");
+            }
+            return;
+        }
+    };
+    println!(
+        "Receiver accepted {} of {} files",
+        prepare_upload_response.files().len(),
+        local_files.len()
+    );
+    let uploads = accepted_uploads(&prepare_upload_response, &local_files);
+    rt.block_on(async {
+        println!("Uploading files");
+        let session_id = prepare_upload_response.session_id();
+        for (path, result) in upload_files(&client, session_id, uploads).await {
+            match result {
+                Ok(()) => println!("sent {:?}", path),
+                Err(error) => println!("failed {:?}: {error}", path),
+            }
+        }
+    });
+}
+
+/// Hand files over to the daemon to send, following the transfer till it ends
+#[cfg(unix)]
+fn send_through_daemon(mut control_client: crate::control::ControlClient, send_args: &SendArgs) {
+    use crate::{
+        control::{Reply, Request},
+        events::Event,
+    };
+    // Daemon may run from elsewhere
+    let paths = match send_args.paths().iter().map(std::path::absolute).collect() {
+        Ok(paths) => paths,
+        Err(error) => {
+            println!("Could not read files to send: {error}");
+            return;
+        }
+    };
+    let request = Request::Send {
+        peer: send_args.receiver().clone(),
+        paths,
+        pin: send_args.pin().clone(),
+        port: Some(send_args.port()),
+        http: send_args.http(),
+        protocol_version: Some(*send_args.protocol_version()),
+    };
+    if let Err(error) = control_client.request(&request) {
+        println!("Could not reach daemon: {error}");
+        return;
+    }
+    match control_client.read::<Reply>() {
+        Ok(Reply::Sending { transfer_id }) => {
+            println!("Daemon is sending, transfer {transfer_id}");
+            println!("Waiting for receiver to accept");
+        }
+        Ok(Reply::Error { message }) => {
+            println!("{message}");
+            return;
+        }
+        Ok(reply) => {
+            println!("Unexpected reply from daemon: {reply:?}");
+            return;
+        }
+        Err(error) => {
+            println!("Could not reach daemon: {error}");
+            return;
+        }
+    }
+    loop {
+        let event = match control_client.read::<Event>() {
+            Ok(event) => event,
+            Err(error) => {
+                println!("Lost daemon: {error}");
+                return;
+            }
+        };
+        match &event {
+            Event::SendAccepted { files, .. } => println!("Receiver accepted {files} files"),
+            Event::FileSent { file_name, .. } => println!("sent {file_name:?}"),
+            Event::FileSendFailed {
+                file_name, reason, ..
+            } => println!("failed {file_name:?}: {reason}"),
+            Event::SendFailed { reason, .. } => println!("{reason}"),
+            Event::SendCancelled { .. } => println!("Cancelled"),
+            _ => {}
+        }
+        if event.ends_transfer() {
+            return;
+        }
+    }
+}
+
+/// Request to send local files, telling the port and protocol we listen on, if we do
+pub(crate) fn prepare_upload_request(
+    local_files: &HashMap<FileId, (FileInfo, PathBuf)>,
+    listening: Option<(u16, Protocol)>,
+) -> PrepareUploadRequest {
+    let device_info = PrepareUploadDeviceInfo::from(load_state().device_info);
+    let device_info = match listening {
+        Some((port, protocol)) => device_info.with_port(port).with_protocol(protocol),
+        None => device_info,
+    };
+    PrepareUploadRequest::from_files(
+        device_info,
+        local_files
+            .values()
+            .map(|(file_info, _path)| file_info.clone()),
+    )
+}
+
+/// Files receiver accepted, with their tokens and where they are
+pub(crate) fn accepted_uploads(
+    prepare_upload_response: &PrepareUploadResponse,
+    local_files: &HashMap<FileId, (FileInfo, PathBuf)>,
+) -> Vec<(FileId, FileUploadToken, PathBuf)> {
+    prepare_upload_response
+        .files()
+        .iter()
+        .filter_map(|(file_id, token)| {
+            let (_file_info, path) = local_files.get(file_id)?;
+            Some((file_id.clone(), token.clone(), path.clone()))
+        })
+        .collect()
+}
+
+/// Upload files concurrently, returning how each went (failures described)
+pub(crate) async fn upload_files(
+    client: &LocalSendClient,
+    session_id: &SessionId,
+    uploads: Vec<(FileId, FileUploadToken, PathBuf)>,
+) -> Vec<(PathBuf, Result<(), String>)> {
+    let mut joinset = tokio::task::JoinSet::new();
+    for (file_id, token, path) in uploads {
+        let upload = upload_single_file(
+            client.clone(),
+            session_id.clone(),
+            file_id,
+            token,
+            path.clone(),
+        );
+        joinset.spawn(async move { (path, upload.await.map_err(|error| error.to_string())) });
+    }
+    joinset.join_all().await
+}
+
+/// Upload given file as per localsend protocol.
+/// Like downloads, this may run as a separate task, so we take ownership of our inputs.
+#[funsie_errors]
+#[errorset(ClientError, IoError)]
+async fn upload_single_file(
+    client: LocalSendClient,
+    session_id: SessionId,
+    file_id: FileId,
+    token: FileUploadToken,
+    path: PathBuf,
+) -> Result<(), _> {
+    let open_file = tokio::fs::File::open(&path).await?;
+    client
+        .upload(&session_id, &file_id, &token, open_file)
+        .await?;
+    Ok(())
+}
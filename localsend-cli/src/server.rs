@@ -0,0 +1,157 @@
+use std::{
+    future::IntoFuture,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Router,
+};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use localsend_lib_types::{
+    errors::{PrepareDownloadError, PrepareUploadError, UploadError},
+    messages::common_fields::Protocol,
+};
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivatePkcs8KeyDer},
+    ServerConfig,
+};
+use socket2::{Domain, Protocol as SocketProtocol, Socket, Type};
+use std::io::Error as IoError;
+use tokio_util::sync::CancellationToken;
+
+use crate::state::{load_certificate, Certificate};
+
+/// Serve given routes on all interfaces at port, until the server fails or shutdown is cancelled.
+/// Https is served using our self-signed certificate, which our fingerprint is derived from.
+/// Handlers may extract `ConnectInfo<SocketAddr>` to learn the peer address.
+/// Ipv4 peers show up as ipv4-mapped ipv6 addresses, unless canonicalized.
+pub async fn serve(
+    routes: Router,
+    port: u16,
+    protocol: Protocol,
+    shutdown: CancellationToken,
+) -> Result<(), IoError> {
+    serve_on(bind_all_interfaces(port)?, routes, protocol, shutdown).await
+}
+
+/// Serve given routes on listener (ex bound to a specific address),
+/// until the server fails or shutdown is cancelled.
+/// On shutdown, requests in flight are given SHUTDOWN_GRACE to complete.
+pub async fn serve_on(
+    listener: TcpListener,
+    routes: Router,
+    protocol: Protocol,
+    shutdown: CancellationToken,
+) -> Result<(), IoError> {
+    let make_service = routes.into_make_service_with_connect_info::<SocketAddr>();
+    listener.set_nonblocking(true)?;
+    match protocol {
+        Protocol::Http => {
+            let serving = axum::serve(tokio::net::TcpListener::from_std(listener)?, make_service)
+                .with_graceful_shutdown(shutdown.clone().cancelled_owned());
+            // Graceful shutdown waits on every connection, so bound it as for https
+            let grace_over = async {
+                shutdown.cancelled().await;
+                tokio::time::sleep(SHUTDOWN_GRACE).await;
+            };
+            tokio::select! {
+                served = serving.into_future() => served,
+                () = grace_over => Ok(()),
+            }
+        }
+        Protocol::Https => {
+            let tls_config = RustlsConfig::from_config(Arc::new(tls_config(&load_certificate())?));
+            let handle = Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown.cancelled().await;
+                    handle.graceful_shutdown(Some(SHUTDOWN_GRACE));
+                }
+            });
+            axum_server::from_tcp_rustls(listener, tls_config)
+                .handle(handle)
+                .serve(make_service)
+                .await
+        }
+    }
+}
+
+/// How long requests in flight (ex uploads) may go on, once shutting down
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// Listen at port on all interfaces, ipv6 and ipv4 both (dual stack) where the system allows,
+/// only ipv4 otherwise
+fn bind_all_interfaces(port: u16) -> Result<TcpListener, IoError> {
+    let bind = |domain: Domain, address: SocketAddr| -> Result<Socket, IoError> {
+        let socket = Socket::new(domain, Type::STREAM, Some(SocketProtocol::TCP))?;
+        if domain == Domain::IPV6 {
+            socket.set_only_v6(false)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.bind(&address.into())?;
+        socket.listen(1024)?;
+        Ok(socket)
+    };
+    let socket = bind(Domain::IPV6, (Ipv6Addr::UNSPECIFIED, port).into())
+        .or_else(|_| bind(Domain::IPV4, (Ipv4Addr::UNSPECIFIED, port).into()))?;
+    Ok(socket.into())
+}
+
+fn tls_config(certificate: &Certificate) -> Result<ServerConfig, IoError> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("ring provider should support default protocol versions")
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from(certificate.cert_der.clone())],
+            PrivatePkcs8KeyDer::from(certificate.key_der.clone()).into(),
+        )
+        .map_err(IoError::other)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Error reply of a localsend api endpoint.
+/// Carries the status code, as documented by the protocol, and the reason as body.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    reason: String,
+}
+
+impl ApiError {
+    pub fn new(status_code: u16, reason: String) -> Self {
+        let status = StatusCode::from_u16(status_code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        ApiError { status, reason }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, self.reason).into_response()
+    }
+}
+
+impl From<PrepareDownloadError> for ApiError {
+    fn from(error: PrepareDownloadError) -> Self {
+        ApiError::new(error.status_code(), error.to_string())
+    }
+}
+
+impl From<PrepareUploadError> for ApiError {
+    fn from(error: PrepareUploadError) -> Self {
+        ApiError::new(error.status_code(), error.to_string())
+    }
+}
+
+impl From<UploadError> for ApiError {
+    fn from(error: UploadError) -> Self {
+        ApiError::new(error.status_code(), error.to_string())
+    }
+}
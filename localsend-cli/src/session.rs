@@ -0,0 +1,426 @@
+//! Incoming (upload) sessions, as the receiver keeps track of them.
+//! As per protocol, only one session is served at a time: others get a 409 meanwhile.
+//! A session is pending until decided on, then its accepted files are uploaded one by one,
+//! each with its token, from the address that prepared the session.
+//!
+//! No sockets here, callers pass what they got from the request, and the time.
+//! Callers also expire idle sessions, before acting on requests.
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use localsend_lib_types::{
+    api::UploadParams,
+    errors::{PrepareUploadError, UploadError},
+    messages::common_fields::{
+        DeviceInfo, FileId, FileInfo, FileUploadToken, FilesTokenMap, SessionId,
+    },
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+/// Decision on a pending session
+#[derive(Debug)]
+pub enum Decision {
+    /// Accept given files, all if none given
+    Accept(Option<Vec<FileId>>),
+    Reject,
+}
+
+/// Incoming session, as reported to the control api
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IncomingSession {
+    pub session_id: SessionId,
+    pub sender: DeviceInfo,
+    pub sender_address: IpAddr,
+    /// Awaiting a decision, rather than accepted
+    pub pending: bool,
+    /// Files yet to be received (or offered, if pending)
+    pub files: Vec<FileInfo>,
+    /// Bytes received so far, of all files
+    #[serde(default)]
+    pub received_bytes: u64,
+}
+
+/// Where a file of the session is at
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FileStatus {
+    /// Offered, or accepted and yet to be uploaded
+    Waiting,
+    Receiving,
+    Received,
+    /// Upload broke off, sender may try again
+    Failed,
+}
+
+struct IncomingFile {
+    file_info: FileInfo,
+    token: FileUploadToken,
+    status: FileStatus,
+    /// Bytes received so far
+    received: u64,
+}
+
+struct UploadSession {
+    session_id: SessionId,
+    sender: DeviceInfo,
+    sender_address: IpAddr,
+    files: HashMap<FileId, IncomingFile>,
+    /// Set while session awaits a decision
+    decide: Option<oneshot::Sender<Decision>>,
+    last_activity: Instant,
+}
+
+/// The receiver's current session, if any
+pub struct SessionManager {
+    current: Option<UploadSession>,
+    /// Session left without activity for this long is dropped
+    idle_timeout: Duration,
+}
+
+impl Default for SessionManager {
+    fn default() -> Self {
+        SessionManager::new(SESSION_IDLE_TIMEOUT)
+    }
+}
+
+impl SessionManager {
+    pub fn new(idle_timeout: Duration) -> Self {
+        SessionManager {
+            current: None,
+            idle_timeout,
+        }
+    }
+
+    /// Start session for files offered, pending if decide is given, else accepting all.
+    /// 409 while another session is ongoing, unless it is pending on a sender who gave up.
+    pub fn begin(
+        &mut self,
+        sender: DeviceInfo,
+        sender_address: IpAddr,
+        files: impl IntoIterator<Item = FileInfo>,
+        decide: Option<oneshot::Sender<Decision>>,
+        now: Instant,
+    ) -> Result<IncomingSession, PrepareUploadError> {
+        let abandoned = self.current.as_ref().is_some_and(|current| {
+            current
+                .decide
+                .as_ref()
+                .is_some_and(|decide| decide.is_closed())
+        });
+        if self.current.is_some() && !abandoned {
+            return Err(PrepareUploadError::Http409);
+        }
+        let files: HashMap<FileId, IncomingFile> = files
+            .into_iter()
+            .map(|file_info| {
+                let incoming_file = IncomingFile {
+                    token: uuid::Uuid::new_v4().to_string().into(),
+                    file_info,
+                    status: FileStatus::Waiting,
+                    received: 0,
+                };
+                (incoming_file.file_info.id().clone(), incoming_file)
+            })
+            .collect();
+        if files.is_empty() {
+            return Err(PrepareUploadError::Http204);
+        }
+        let session = UploadSession {
+            session_id: uuid::Uuid::new_v4().to_string().into(),
+            sender,
+            sender_address,
+            files,
+            decide,
+            last_activity: now,
+        };
+        let summary = session.summary();
+        self.current = Some(session);
+        Ok(summary)
+    }
+
+    /// Current session, if any
+    pub fn current(&self) -> Option<IncomingSession> {
+        self.current.as_ref().map(UploadSession::summary)
+    }
+
+    /// Decide on pending session. False if there is no such session pending.
+    pub fn decide(&mut self, session_id: &SessionId, decision: Decision) -> bool {
+        match self
+            .session_mut(session_id)
+            .and_then(|current| current.decide.take())
+        {
+            Some(decide) => decide.send(decision).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Keep only accepted files of session (all if none given), returning their tokens.
+    /// None if session is gone, or no files are left (ending it).
+    pub fn accept(
+        &mut self,
+        session_id: &SessionId,
+        files: Option<Vec<FileId>>,
+        now: Instant,
+    ) -> Option<FilesTokenMap> {
+        let current = self.session_mut(session_id)?;
+        if let Some(accepted) = files {
+            current
+                .files
+                .retain(|file_id, _| accepted.contains(file_id));
+        }
+        current.decide = None;
+        current.last_activity = now;
+        if current.files.is_empty() {
+            self.current = None;
+            return None;
+        }
+        Some(
+            current
+                .files
+                .iter()
+                .map(|(file_id, incoming_file)| (file_id.clone(), incoming_file.token.clone()))
+                .collect(),
+        )
+    }
+
+    /// Check upload params (and where they came from) against the session,
+    /// and mark file as being received, returning its info.
+    pub fn start_upload(
+        &mut self,
+        params: &UploadParams,
+        sender_address: IpAddr,
+        now: Instant,
+    ) -> Result<FileInfo, UploadError> {
+        let Some(current) = self.current.as_mut() else {
+            return Err(UploadError::Http403);
+        };
+        if &current.session_id != params.session_id() {
+            return Err(UploadError::Http409);
+        }
+        if current.decide.is_some() || current.sender_address != sender_address {
+            return Err(UploadError::Http403);
+        }
+        let Some(incoming_file) = current.files.get_mut(params.file_id()) else {
+            return Err(UploadError::Http403);
+        };
+        if &incoming_file.token != params.token()
+            || !matches!(
+                incoming_file.status,
+                FileStatus::Waiting | FileStatus::Failed
+            )
+        {
+            return Err(UploadError::Http403);
+        }
+        incoming_file.status = FileStatus::Receiving;
+        incoming_file.received = 0;
+        current.last_activity = now;
+        Ok(incoming_file.file_info.clone())
+    }
+
+    /// Count bytes received for file being received
+    pub fn progress(&mut self, session_id: &SessionId, file_id: &FileId, bytes: u64, now: Instant) {
+        if let Some(current) = self.session_mut(session_id) {
+            if let Some(incoming_file) = current.files.get_mut(file_id) {
+                incoming_file.received += bytes;
+                current.last_activity = now;
+            }
+        }
+    }
+
+    /// Mark file as received or failed, returning whether that completes the session (ending it).
+    /// None if the session ended meanwhile (cancelled or expired).
+    pub fn finish_upload(
+        &mut self,
+        session_id: &SessionId,
+        file_id: &FileId,
+        received: bool,
+        now: Instant,
+    ) -> Option<bool> {
+        let current = self.session_mut(session_id)?;
+        let incoming_file = current.files.get_mut(file_id)?;
+        incoming_file.status = if received {
+            FileStatus::Received
+        } else {
+            FileStatus::Failed
+        };
+        current.last_activity = now;
+        let complete = current
+            .files
+            .values()
+            .all(|incoming_file| incoming_file.status == FileStatus::Received);
+        if complete {
+            self.current = None;
+        }
+        Some(complete)
+    }
+
+    /// End session if it has given id (or none is given), returning its id.
+    /// Uploads in flight get refused when they complete.
+    pub fn cancel(&mut self, session_id: Option<&SessionId>) -> Option<SessionId> {
+        let current = self.current.as_ref()?;
+        if session_id.is_some_and(|session_id| session_id != &current.session_id) {
+            return None;
+        }
+        self.current.take().map(|current| current.session_id)
+    }
+
+    /// End session left idle for too long, returning its id
+    pub fn expire_idle(&mut self, now: Instant) -> Option<SessionId> {
+        let current = self.current.as_ref()?;
+        if now.saturating_duration_since(current.last_activity) < self.idle_timeout {
+            return None;
+        }
+        self.current.take().map(|current| current.session_id)
+    }
+
+    fn session_mut(&mut self, session_id: &SessionId) -> Option<&mut UploadSession> {
+        self.current
+            .as_mut()
+            .filter(|current| &current.session_id == session_id)
+    }
+}
+
+impl UploadSession {
+    fn summary(&self) -> IncomingSession {
+        let mut files: Vec<FileInfo> = self
+            .files
+            .values()
+            .filter(|incoming_file| incoming_file.status != FileStatus::Received)
+            .map(|incoming_file| incoming_file.file_info.clone())
+            .collect();
+        files.sort_by(|a, b| a.file_name().cmp(b.file_name()));
+        IncomingSession {
+            session_id: self.session_id.clone(),
+            sender: self.sender.clone(),
+            sender_address: self.sender_address,
+            pending: self.decide.is_some(),
+            files,
+            received_bytes: self
+                .files
+                .values()
+                .map(|incoming_file| incoming_file.received)
+                .sum(),
+        }
+    }
+}
+
+/// How long a session may go without requests (or upload progress) before it is dropped
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str) -> FileInfo {
+        FileInfo::new(
+            name.to_string().into(),
+            name.to_string(),
+            3,
+            "text/plain".to_string(),
+            None,
+            None,
+            None,
+        )
+    }
+
+    fn params(session_id: &SessionId, file_id: &str, tokens: &FilesTokenMap) -> UploadParams {
+        let file_id: FileId = file_id.to_string().into();
+        let token = tokens.token(&file_id).unwrap().clone();
+        UploadParams::new(session_id.clone(), file_id, token)
+    }
+
+    #[test]
+    fn one_session_at_a_time() {
+        let mut sessions = SessionManager::default();
+        let (sender, other) = (
+            "192.168.1.20".parse().unwrap(),
+            "192.168.1.21".parse().unwrap(),
+        );
+        let now = Instant::now();
+        let session = sessions
+            .begin(
+                DeviceInfo::default(),
+                sender,
+                [file("a"), file("b")],
+                None,
+                now,
+            )
+            .unwrap();
+        let blocked = sessions.begin(DeviceInfo::default(), other, [file("c")], None, now);
+        assert_eq!(blocked.unwrap_err(), PrepareUploadError::Http409);
+        let session_id = &session.session_id;
+        let tokens = sessions.accept(session_id, None, now).unwrap();
+
+        let wrong_token = UploadParams::new(
+            session_id.clone(),
+            "a".to_string().into(),
+            "nope".to_string().into(),
+        );
+        assert_eq!(
+            sessions.start_upload(&wrong_token, sender, now),
+            Err(UploadError::Http403)
+        );
+        let a = params(session_id, "a", &tokens);
+        assert_eq!(
+            sessions.start_upload(&a, other, now),
+            Err(UploadError::Http403)
+        );
+        assert!(sessions.start_upload(&a, sender, now).is_ok());
+        // Not twice at once
+        assert_eq!(
+            sessions.start_upload(&a, sender, now),
+            Err(UploadError::Http403)
+        );
+        sessions.progress(session_id, a.file_id(), 3, now);
+        assert_eq!(
+            sessions.finish_upload(session_id, a.file_id(), true, now),
+            Some(false)
+        );
+        let current = sessions.current().unwrap();
+        assert_eq!(current.received_bytes, 3);
+        assert_eq!(current.files, vec![file("b")]);
+        let b = params(session_id, "b", &tokens);
+        assert!(sessions.start_upload(&b, sender, now).is_ok());
+        assert_eq!(
+            sessions.finish_upload(session_id, b.file_id(), false, now),
+            Some(false)
+        );
+        // Failed uploads may be retried
+        assert!(sessions.start_upload(&b, sender, now).is_ok());
+        assert_eq!(
+            sessions.finish_upload(session_id, b.file_id(), true, now),
+            Some(true)
+        );
+        assert!(sessions.current().is_none());
+    }
+
+    #[test]
+    fn idle_and_cancelled_sessions_end() {
+        let mut sessions = SessionManager::new(Duration::from_secs(10));
+        let sender: IpAddr = "192.168.1.20".parse().unwrap();
+        let start = Instant::now();
+        let session = sessions
+            .begin(DeviceInfo::default(), sender, [file("a")], None, start)
+            .unwrap();
+        let tokens = sessions.accept(&session.session_id, None, start).unwrap();
+        let a = params(&session.session_id, "a", &tokens);
+        let later = start + Duration::from_secs(10);
+        assert_eq!(sessions.expire_idle(start + Duration::from_secs(9)), None);
+        assert_eq!(sessions.expire_idle(later), Some(session.session_id));
+        assert_eq!(
+            sessions.start_upload(&a, sender, later),
+            Err(UploadError::Http403)
+        );
+        assert!(sessions
+            .begin(DeviceInfo::default(), sender, [file("a")], None, later)
+            .is_ok());
+
+        let other_session: SessionId = "other".to_string().into();
+        assert_eq!(sessions.cancel(Some(&other_session)), None);
+        assert!(sessions.cancel(None).is_some());
+        assert!(sessions.current().is_none());
+    }
+}
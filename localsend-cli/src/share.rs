@@ -0,0 +1,486 @@
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{rejection::QueryRejection, ConnectInfo, Query, State},
+    http::{
+        header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use localsend_lib_types::{
+    api::{DownloadParams, PrepareDownloadParams},
+    errors::PrepareDownloadError,
+    messages::{
+        common_fields::{DeviceInfo, FileId, FileInfo, FilesInfoMap, Protocol, SessionId, Version},
+        discover::MulticastCommon,
+        download::{PrepareDownloadMeta, PrepareDownloadResponse},
+    },
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
+
+use crate::{
+    args::{OutputFormat, ShareArgs},
+    config::load_config,
+    discover::{announce_broadcast, register_routes, Discovery},
+    multicast::MulticastScope,
+    pin::{choose_pin, PinGuard},
+    server::{serve, ApiError},
+    state::load_state,
+    utils::{collect_files, dbgr},
+};
+
+/// Share files for others to download (download api), using pin if provided.
+/// Refused while a daemon runs, peers would see us announced twice, on different ports.
+pub fn share(share_args: ShareArgs) {
+    #[cfg(unix)]
+    if crate::control::ControlClient::connect().is_some() {
+        println!("A daemon is running, stop it to share files");
+        return;
+    }
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(error) => {
+            println!("{error}");
+            return;
+        }
+    };
+    let device_info = load_state().device_info;
+    let local_files = match collect_files(share_args.paths()) {
+        Ok(local_files) => local_files,
+        Err(error) => {
+            println!("Could not read files to share: {error}");
+            return;
+        }
+    };
+    for (file_info, _path) in local_files.values() {
+        println!(
+            "{}\t{}b\t{}",
+            file_info.file_type(),
+            file_info.size(),
+            file_info.file_name()
+        )
+    }
+    let protocol = if share_args.http() {
+        Protocol::Http
+    } else {
+        Protocol::Https
+    };
+    let announcement = MulticastCommon::new(
+        device_info.clone(),
+        share_args.port().into(),
+        protocol,
+        Some(true),
+    );
+    let _announce_broadcast_handle = thread::spawn({
+        let announcement = announcement.clone();
+        let announce_interval = share_args.announce_interval();
+        let scope = MulticastScope {
+            ipv6: share_args.ipv6(),
+            interfaces: share_args.interface().clone(),
+            exclude_interfaces: share_args.exclude_interface().clone(),
+        };
+        move || announce_broadcast(announcement, announce_interval, scope)
+    });
+    // Peers answering our announcement register back
+    let discovery = Arc::new(Discovery::new(announcement, OutputFormat::Text));
+    let pin = choose_pin(
+        share_args.random_pin(),
+        share_args.pin().clone(),
+        config.pin,
+    );
+    let sharer = Arc::new(Sharer::new(device_info, local_files, pin));
+    let rt = tokio::runtime::Runtime::new().inspect_err(dbgr).unwrap();
+    println!("Sharing above files on port {}", share_args.port());
+    let routes = download_routes(sharer).merge(register_routes(discovery));
+    rt.block_on(serve(
+        routes,
+        share_args.port(),
+        protocol,
+        CancellationToken::new(),
+    ))
+    .inspect_err(dbgr)
+    .unwrap();
+}
+
+/// Routes of the download api (prepare-download, download)
+pub fn download_routes(sharer: Arc<Sharer>) -> Router {
+    Router::new()
+        .route("/api/localsend/v2/prepare-download", post(prepare_download))
+        .route("/api/localsend/v2/download", get(download))
+        .with_state(sharer)
+}
+
+/// Download server state.
+/// Every downloader gets its own session, all sessions share the same files.
+pub struct Sharer {
+    device_info: DeviceInfo,
+    files: HashMap<FileId, (FileInfo, PathBuf)>,
+    pin_guard: PinGuard,
+    sessions: Mutex<DownloadSessions>,
+}
+
+/// Sessions of downloaders, by last activity.
+/// Forgotten once idle for DOWNLOAD_SESSION_IDLE_TIMEOUT, or to make room past MAX_DOWNLOAD_SESSIONS.
+#[derive(Default)]
+struct DownloadSessions {
+    last_activity: HashMap<SessionId, Instant>,
+}
+
+impl DownloadSessions {
+    /// Mark session active, false if there is no such session (anymore)
+    fn touch(&mut self, session_id: &SessionId, now: Instant) -> bool {
+        self.expire_idle(now);
+        match self.last_activity.get_mut(session_id) {
+            Some(last_activity) => {
+                *last_activity = now;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// New session, making room for it if need be
+    fn begin(&mut self, now: Instant) -> SessionId {
+        self.expire_idle(now);
+        if self.last_activity.len() >= MAX_DOWNLOAD_SESSIONS {
+            let longest_idle = self
+                .last_activity
+                .iter()
+                .min_by_key(|(_session_id, last_activity)| **last_activity)
+                .map(|(session_id, _last_activity)| session_id.clone());
+            if let Some(longest_idle) = longest_idle {
+                self.last_activity.remove(&longest_idle);
+            }
+        }
+        let session_id: SessionId = uuid::Uuid::new_v4().to_string().into();
+        self.last_activity.insert(session_id.clone(), now);
+        session_id
+    }
+
+    fn expire_idle(&mut self, now: Instant) {
+        self.last_activity.retain(|_session_id, last_activity| {
+            now.saturating_duration_since(*last_activity) < DOWNLOAD_SESSION_IDLE_TIMEOUT
+        });
+    }
+}
+
+/// How long a downloader may go without requesting anything.
+/// Long, as a single large file may take a while to download.
+const DOWNLOAD_SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const MAX_DOWNLOAD_SESSIONS: usize = 1024;
+
+impl Sharer {
+    pub fn new(
+        device_info: DeviceInfo,
+        files: HashMap<FileId, (FileInfo, PathBuf)>,
+        pin: Option<String>,
+    ) -> Self {
+        Sharer {
+            device_info,
+            files,
+            pin_guard: PinGuard::new(pin),
+            sessions: Mutex::new(DownloadSessions::default()),
+        }
+    }
+
+    fn files_map(&self) -> FilesInfoMap {
+        FilesInfoMap::new(
+            self.files
+                .iter()
+                .map(|(file_id, (file_info, _path))| (file_id.clone(), file_info.clone()))
+                .collect(),
+        )
+    }
+}
+
+async fn prepare_download(
+    State(sharer): State<Arc<Sharer>>,
+    ConnectInfo(downloader_address): ConnectInfo<SocketAddr>,
+    params: Result<Query<PrepareDownloadParams>, QueryRejection>,
+) -> Result<Json<PrepareDownloadResponse>, ApiError> {
+    let given_pin = params
+        .as_ref()
+        .ok()
+        .and_then(|Query(params)| params.pin().as_ref());
+    sharer
+        .pin_guard
+        .check(downloader_address.ip().to_canonical(), given_pin)
+        .map_err(PrepareDownloadError::from)?;
+    let Ok(Query(params)) = params else {
+        return Err(PrepareDownloadError::Http403.into());
+    };
+    let mut sessions = sharer.sessions.lock().expect("sessions lock poisoned");
+    let now = Instant::now();
+    let session_id = match params.session_id() {
+        // Same downloader coming back (ex: browser refresh)
+        Some(session_id) if sessions.touch(session_id, now) => session_id.clone(),
+        _ => {
+            let session_id = sessions.begin(now);
+            println!(
+                "Files requested by {}, session {session_id}",
+                downloader_address.ip().to_canonical()
+            );
+            session_id
+        }
+    };
+    Ok(Json(PrepareDownloadResponse::new(
+        PrepareDownloadMeta::new(
+            Version::default(),
+            sharer.device_info.clone(),
+            serde_bool::True,
+        ),
+        session_id,
+        sharer.files_map(),
+    )))
+}
+
+async fn download(
+    State(sharer): State<Arc<Sharer>>,
+    params: Result<Query<DownloadParams>, QueryRejection>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let Ok(Query(params)) = params else {
+        return Err(PrepareDownloadError::Http403.into());
+    };
+    let session_known = sharer
+        .sessions
+        .lock()
+        .expect("sessions lock poisoned")
+        .touch(params.session_id(), Instant::now());
+    let Some((file_info, path)) = sharer.files.get(params.file_id()).filter(|_| session_known)
+    else {
+        return Err(PrepareDownloadError::Http403.into());
+    };
+    let mut open_file = tokio::fs::File::open(path)
+        .await
+        .inspect_err(dbgr)
+        .map_err(|_| PrepareDownloadError::Http500)?;
+    let size = open_file
+        .metadata()
+        .await
+        .inspect_err(dbgr)
+        .map_err(|_| PrepareDownloadError::Http500)?
+        .len();
+    let response = match requested_range(&headers, size) {
+        RequestedRange::Whole => {
+            println!("serving {:?}", path);
+            (
+                [
+                    (CONTENT_TYPE, file_info.file_type().clone()),
+                    (CONTENT_LENGTH, size.to_string()),
+                    (ACCEPT_RANGES, "bytes".to_string()),
+                ],
+                Body::from_stream(ReaderStream::new(open_file)),
+            )
+                .into_response()
+        }
+        RequestedRange::Part(start, end) => {
+            println!("serving {:?} from byte {start}", path);
+            open_file
+                .seek(SeekFrom::Start(start))
+                .await
+                .inspect_err(dbgr)
+                .map_err(|_| PrepareDownloadError::Http500)?;
+            let length = end - start + 1;
+            (
+                StatusCode::PARTIAL_CONTENT,
+                [
+                    (CONTENT_TYPE, file_info.file_type().clone()),
+                    (CONTENT_LENGTH, length.to_string()),
+                    (CONTENT_RANGE, format!("bytes {start}-{end}/{size}")),
+                ],
+                Body::from_stream(ReaderStream::new(open_file.take(length))),
+            )
+                .into_response()
+        }
+        RequestedRange::Unsatisfiable => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(CONTENT_RANGE, format!("bytes */{size}"))],
+        )
+            .into_response(),
+    };
+    Ok(response)
+}
+
+/// Part of file asked for by `Range` header
+#[derive(Debug, PartialEq)]
+enum RequestedRange {
+    Whole,
+    /// First and last byte, inclusive
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+/// Supports a single `bytes=start-[end]` or `bytes=-suffix_length` range.
+/// Anything else (ex: multiple ranges) is answered with the whole file, as http allows.
+fn requested_range(headers: &HeaderMap, size: u64) -> RequestedRange {
+    let Some(range) = headers.get(RANGE).and_then(|range| range.to_str().ok()) else {
+        return RequestedRange::Whole;
+    };
+    let Some((start, end)) = range
+        .trim()
+        .strip_prefix("bytes=")
+        .and_then(|range| range.split_once('-'))
+    else {
+        return RequestedRange::Whole;
+    };
+    let (start, end) = match (start.trim().parse::<u64>(), end.trim().parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.trim().is_empty() => (start, size.saturating_sub(1)),
+        (Err(_), Ok(suffix_length)) if start.trim().is_empty() && suffix_length > 0 => {
+            (size.saturating_sub(suffix_length), size.saturating_sub(1))
+        }
+        _ => return RequestedRange::Whole,
+    };
+    if start >= size {
+        return RequestedRange::Unsatisfiable;
+    }
+    RequestedRange::Part(start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv6Addr, TcpListener};
+
+    use localsend_client::LocalSendClient;
+
+    use super::*;
+    use crate::{server::serve_on, utils::peer_url};
+
+    #[test]
+    fn download_sessions_expire_and_make_room() {
+        let mut sessions = DownloadSessions::default();
+        let start = Instant::now();
+        let first = sessions.begin(start);
+        assert!(sessions.touch(&first, start + Duration::from_secs(60)));
+        // Idle since its last request
+        let idle_until = start + Duration::from_secs(60) + DOWNLOAD_SESSION_IDLE_TIMEOUT;
+        assert!(!sessions.touch(&first, idle_until));
+        assert!(sessions.last_activity.is_empty());
+        // Downloaders looping on prepare-download don't grow it without end
+        let oldest = sessions.begin(start);
+        for _ in 0..MAX_DOWNLOAD_SESSIONS {
+            sessions.begin(start + Duration::from_secs(1));
+        }
+        assert_eq!(sessions.last_activity.len(), MAX_DOWNLOAD_SESSIONS);
+        assert!(!sessions.touch(&oldest, start + Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn download_over_ipv6_loopback() {
+        let source = tempfile::tempdir().unwrap();
+        let source_file = source.path().join("hello.txt");
+        std::fs::write(&source_file, "hello over ipv6").unwrap();
+        let listener = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sharer = Arc::new(Sharer::new(
+            DeviceInfo::default(),
+            collect_files(&[source_file]).unwrap(),
+            None,
+        ));
+        tokio::spawn(serve_on(
+            listener,
+            download_routes(sharer),
+            Protocol::Http,
+            CancellationToken::new(),
+        ));
+
+        let client =
+            LocalSendClient::new(peer_url(Protocol::Http, Ipv6Addr::LOCALHOST.into(), port))
+                .unwrap();
+        let response = client.prepare_download(None).await.unwrap();
+        let file_info = response.files().as_ref().values().next().unwrap();
+        let mut downloaded = Vec::new();
+        client
+            .download(response.session_id(), file_info, &mut downloaded)
+            .await
+            .unwrap();
+        assert_eq!(downloaded, b"hello over ipv6");
+    }
+
+    #[tokio::test]
+    async fn resume_download_over_ipv6_loopback() {
+        let source = tempfile::tempdir().unwrap();
+        let source_file = source.path().join("hello.txt");
+        std::fs::write(&source_file, "hello over ipv6").unwrap();
+        let listener = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sharer = Arc::new(Sharer::new(
+            DeviceInfo::default(),
+            collect_files(&[source_file]).unwrap(),
+            None,
+        ));
+        tokio::spawn(serve_on(
+            listener,
+            download_routes(sharer),
+            Protocol::Http,
+            CancellationToken::new(),
+        ));
+
+        let client =
+            LocalSendClient::new(peer_url(Protocol::Http, Ipv6Addr::LOCALHOST.into(), port))
+                .unwrap();
+        let response = client.prepare_download(None).await.unwrap();
+        let file_info = response.files().as_ref().values().next().unwrap();
+        let part_path = source.path().join("hello.txt.part");
+        std::fs::write(&part_path, "hello ").unwrap();
+        let mut part_file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&part_path)
+            .await
+            .unwrap();
+        let written = client
+            .resume_download(response.session_id(), file_info, &mut part_file)
+            .await
+            .unwrap();
+        // Only the missing tail came over
+        assert_eq!(written, "over ipv6".len() as u64);
+        assert_eq!(std::fs::read(&part_path).unwrap(), b"hello over ipv6");
+        // Complete already, only verified
+        let written = client
+            .resume_download(response.session_id(), file_info, &mut part_file)
+            .await
+            .unwrap();
+        assert_eq!(written, 0);
+    }
+
+    #[test]
+    fn requested_range_parse() {
+        let range = |range: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RANGE, range.parse().unwrap());
+            requested_range(&headers, 100)
+        };
+        assert_eq!(
+            requested_range(&HeaderMap::new(), 100),
+            RequestedRange::Whole
+        );
+        assert_eq!(range("bytes=10-19"), RequestedRange::Part(10, 19));
+        assert_eq!(range("bytes=90-200"), RequestedRange::Part(90, 99));
+        // Open ended
+        assert_eq!(range("bytes=40-"), RequestedRange::Part(40, 99));
+        // Suffix, last bytes
+        assert_eq!(range("bytes=-30"), RequestedRange::Part(70, 99));
+        assert_eq!(range("bytes=-300"), RequestedRange::Part(0, 99));
+        // Multiple ranges, served whole
+        assert_eq!(range("bytes=0-9,20-29"), RequestedRange::Whole);
+        assert_eq!(range("items=0-9"), RequestedRange::Whole);
+        // Start at or past the end
+        assert_eq!(range("bytes=100-"), RequestedRange::Unsatisfiable);
+        assert_eq!(range("bytes=150-160"), RequestedRange::Unsatisfiable);
+    }
+}
@@ -1,8 +1,19 @@
+use std::{
+    fs::OpenOptions,
+    io::{Error as IoError, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use convert_case::{Case, Casing};
 use directories::ProjectDirs;
-use localsend_lib_types::messages::common_fields::{DeviceInfo, DeviceType};
+use localsend_client::tls::certificate_fingerprint;
+use localsend_lib_types::messages::common_fields::{DeviceInfo, DeviceType, Fingerprint};
+use rcgen::CertifiedKey;
 use serde::{Deserialize, Serialize};
 
+use crate::discover::PeerInfo;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct State {
     pub(crate) device_info: DeviceInfo,
@@ -15,7 +26,7 @@ impl Default for State {
             .next()
             .expect("Infinite names")
             .to_case(Case::Title);
-        let fingerprint = uuid::Uuid::new_v4().to_string();
+        let fingerprint = load_certificate().fingerprint();
         // Detect
         //let device_model = std::env::consts::OS.to_string().to_case(Case::Pascal);
         // Fixed
@@ -26,7 +37,7 @@ impl Default for State {
             alias.into(),
             Some(device_model.into()),
             device_type,
-            fingerprint.into(),
+            fingerprint,
         );
         State { device_info }
     }
@@ -37,8 +48,23 @@ pub(crate) fn load_state() -> State {
         let generated_state_dir = project_dirs.data_local_dir();
         let generated_state_file = generated_state_dir.join("state.toml");
         if generated_state_file.exists() {
-            if let Ok(state_string) = std::fs::read_to_string(generated_state_file) {
-                let state: State = toml::from_str(&state_string).expect("state altered?");
+            if let Ok(state_string) = std::fs::read_to_string(&generated_state_file) {
+                let mut state: State = toml::from_str(&state_string).expect("state altered?");
+                // State from before https, or certificate regenerated
+                let fingerprint = load_certificate().fingerprint();
+                if state.device_info.fingerprint() != &fingerprint {
+                    let device_info = state.device_info;
+                    state.device_info = DeviceInfo::new(
+                        device_info.alias().clone(),
+                        device_info.device_model().clone(),
+                        device_info.device_type().clone(),
+                        fingerprint,
+                    );
+                    let _ = std::fs::write(
+                        generated_state_file,
+                        toml::to_string_pretty(&state).unwrap(),
+                    );
+                }
                 return state;
             }
         } else {
@@ -53,3 +79,261 @@ pub(crate) fn load_state() -> State {
     };
     State::default()
 }
+
+/// Our self-signed tls certificate and its private key, der encoded
+pub(crate) struct Certificate {
+    pub(crate) cert_der: Vec<u8>,
+    pub(crate) key_der: Vec<u8>,
+}
+
+impl Certificate {
+    fn generate() -> Self {
+        let CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localsend-rs".to_string()])
+                .expect("self-signed certificate generation shouldn't fail");
+        Certificate {
+            cert_der: cert.der().to_vec(),
+            key_der: key_pair.serialize_der(),
+        }
+    }
+
+    /// What https peers know us by
+    pub(crate) fn fingerprint(&self) -> Fingerprint {
+        certificate_fingerprint(&self.cert_der)
+    }
+}
+
+/// Load our certificate from state dir, generating (and storing) it on first use
+pub(crate) fn load_certificate() -> Certificate {
+    if let Some(project_dirs) = ProjectDirs::from("", "Nainapps", "localsend-rs") {
+        let generated_state_dir = project_dirs.data_local_dir();
+        let cert_file = generated_state_dir.join("cert.der");
+        let key_file = generated_state_dir.join("key.der");
+        if let (Ok(cert_der), Ok(key_der)) = (std::fs::read(&cert_file), std::fs::read(&key_file)) {
+            return Certificate { cert_der, key_der };
+        }
+        let certificate = Certificate::generate();
+        if let Err(error) = store_certificate(generated_state_dir, &certificate) {
+            eprintln!("Could not store our certificate, peers will know us by another fingerprint next time: {error}");
+        }
+        return certificate;
+    };
+    Certificate::generate()
+}
+
+/// Store certificate in dir, its private key readable by us alone.
+/// Key goes first, so a certificate on disk always has its key.
+fn store_certificate(dir: &Path, certificate: &Certificate) -> Result<(), IoError> {
+    std::fs::create_dir_all(dir)?;
+    let key_file = dir.join("key.der");
+    // Left over without its certificate
+    match std::fs::remove_file(&key_file) {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => return Err(error),
+        _ => {}
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut key = options.open(&key_file)?;
+    key.write_all(&certificate.key_der)?;
+    key.sync_all()?;
+    std::fs::write(dir.join("cert.der"), &certificate.cert_der)
+}
+
+/// Peer seen before, as remembered across runs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct KnownPeer {
+    #[serde(flatten)]
+    pub(crate) peer_info: PeerInfo,
+    /// Our own name for the peer, resolvable like its alias
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) nickname: Option<String>,
+    /// Marked trusted by us
+    #[serde(default)]
+    pub(crate) trusted: bool,
+    /// Unix time, in seconds
+    #[serde(default)]
+    pub(crate) first_seen: u64,
+    /// Unix time, in seconds. Address, port and protocol are as last seen.
+    #[serde(default)]
+    pub(crate) last_seen: u64,
+}
+
+impl KnownPeer {
+    pub(crate) fn fingerprint(&self) -> &Fingerprint {
+        self.peer_info.device_info.fingerprint()
+    }
+
+    /// Nickname if we gave one, else alias
+    pub(crate) fn name(&self) -> String {
+        match &self.nickname {
+            Some(nickname) => nickname.clone(),
+            None => self.peer_info.device_info.alias().to_string(),
+        }
+    }
+
+    /// Stale peers are forgotten, unless we nicknamed or trusted them
+    fn is_expired(&self, now: u64) -> bool {
+        self.nickname.is_none()
+            && !self.trusted
+            && now.saturating_sub(self.last_seen) > PEER_EXPIRY.as_secs()
+    }
+}
+
+/// Peers seen recently, so they can be resolved by alias or fingerprint without discovery
+#[derive(Serialize, Deserialize, Default)]
+struct KnownPeers {
+    peers: Vec<KnownPeer>,
+}
+
+/// How long a peer not seen is remembered
+const PEER_EXPIRY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+fn known_peers_file() -> Option<PathBuf> {
+    ProjectDirs::from("", "Nainapps", "localsend-rs")
+        .map(|project_dirs| project_dirs.data_local_dir().join("peers.toml"))
+}
+
+/// Peers known, leaving out expired ones
+pub(crate) fn load_known_peers() -> Vec<KnownPeer> {
+    let now = unix_now();
+    known_peers_file()
+        .and_then(|known_peers_file| std::fs::read_to_string(known_peers_file).ok())
+        .and_then(|known_peers_string| toml::from_str::<KnownPeers>(&known_peers_string).ok())
+        .map(|known_peers| known_peers.peers)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|known_peer| !known_peer.is_expired(now))
+        .collect()
+}
+
+/// Replace known peers with given ones
+pub(crate) fn save_known_peers(peers: Vec<KnownPeer>) {
+    let Some(known_peers_file) = known_peers_file() else {
+        return;
+    };
+    if let Some(generated_state_dir) = known_peers_file.parent() {
+        let _ = std::fs::create_dir_all(generated_state_dir);
+    }
+    let _ = std::fs::write(
+        known_peers_file,
+        toml::to_string_pretty(&KnownPeers { peers }).unwrap(),
+    );
+}
+
+/// Remember peers as just seen, updating what we knew of them (same fingerprint)
+pub(crate) fn remember_peers(peers: impl IntoIterator<Item = PeerInfo>) {
+    let mut known_peers = load_known_peers();
+    let now = unix_now();
+    for peer_info in peers {
+        remember_peer(&mut known_peers, peer_info, now);
+    }
+    save_known_peers(known_peers);
+}
+
+/// Record peer as seen now, keeping what we set on it before
+pub(crate) fn remember_peer(known_peers: &mut Vec<KnownPeer>, peer_info: PeerInfo, now: u64) {
+    let fingerprint = peer_info.device_info.fingerprint();
+    match known_peers
+        .iter_mut()
+        .find(|known_peer| known_peer.fingerprint() == fingerprint)
+    {
+        Some(known_peer) => {
+            known_peer.peer_info = peer_info;
+            known_peer.last_seen = now;
+        }
+        None => known_peers.push(KnownPeer {
+            peer_info,
+            nickname: None,
+            trusted: false,
+            first_seen: now,
+            last_seen: now,
+        }),
+    }
+}
+
+/// Seconds since unix epoch
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use localsend_lib_types::messages::common_fields::{Protocol, Version};
+
+    use super::*;
+
+    #[test]
+    fn certificate_key_kept_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let certificate = Certificate::generate();
+        std::fs::write(dir.path().join("key.der"), b"stale").unwrap();
+        store_certificate(dir.path(), &certificate).unwrap();
+        let key_der = std::fs::read(dir.path().join("key.der")).unwrap();
+        assert_eq!(key_der, certificate.key_der);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(dir.path().join("key.der")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    fn peer_info(alias: &str, fingerprint: &str, address: Ipv4Addr) -> PeerInfo {
+        PeerInfo {
+            device_info: DeviceInfo::new(
+                alias.to_string().into(),
+                None,
+                DeviceType::Headless,
+                Fingerprint::new(fingerprint.to_string()),
+            ),
+            address: IpAddr::V4(address),
+            port: 53317.into(),
+            protocol: Protocol::Https,
+            version: Version::default(),
+            download_mode: false,
+            interface: None,
+            scope_id: None,
+        }
+    }
+
+    #[test]
+    fn remember_keeps_our_marks_and_expires_stale_peers() {
+        let mut known_peers = Vec::new();
+        remember_peer(
+            &mut known_peers,
+            peer_info("Laptop", "aaaa", Ipv4Addr::new(192, 168, 1, 2)),
+            100,
+        );
+        known_peers[0].nickname = Some("work".to_string());
+        remember_peer(
+            &mut known_peers,
+            peer_info("Phone", "bbbb", Ipv4Addr::new(192, 168, 1, 3)),
+            100,
+        );
+        remember_peer(
+            &mut known_peers,
+            peer_info("Laptop", "aaaa", Ipv4Addr::new(192, 168, 1, 9)),
+            200,
+        );
+        assert_eq!(known_peers.len(), 2);
+        let laptop = &known_peers[0];
+        assert_eq!(laptop.name(), "work");
+        assert_eq!(laptop.peer_info.address, Ipv4Addr::new(192, 168, 1, 9));
+        assert_eq!((laptop.first_seen, laptop.last_seen), (100, 200));
+
+        let later = 200 + PEER_EXPIRY.as_secs() + 1;
+        assert!(
+            !known_peers[0].is_expired(later),
+            "nicknamed peers are kept"
+        );
+        assert!(known_peers[1].is_expired(later));
+    }
+}
@@ -1,6 +1,20 @@
-use std::{fmt::Debug, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    fs::FileTimes,
+    future::Future,
+    io::IsTerminal,
+    net::{IpAddr, SocketAddr},
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
 
+use localsend_client::{errors::ClientError, hash::sha256_file};
+use localsend_lib_types::messages::common_fields::{FileId, FileInfo, FileMeta, Protocol};
 use path_clean::PathClean;
+use std::io::Error as IoError;
+use tokio::io::{AsyncBufRead, Lines};
+use url::Url;
 
 pub fn dbgr<V>(value: &V)
 where
@@ -10,42 +24,261 @@ where
 }
 
 /// Present query to user, and wait for confirmation (y/n)
-/// If default provided, will highlight that option
+/// If default provided, will highlight that option, and an empty answer picks it.
+/// Errors with UnexpectedEof once stdin is closed, as no one is there to answer.
 pub fn ask_confirm(query: &str, default: Option<bool>) -> Result<bool, std::io::Error> {
-    let options_text = if let Some(default_yes) = default {
-        if default_yes {
-            "(Y/n)"
-        } else {
-            "(y/N)"
-        }
-    } else {
-        "(y/n)"
-    };
     loop {
-        println!("{} {}", query, options_text);
+        println!("{} {}", query, options_text(default));
         let mut buffer = String::new();
 
         // `read_line` returns `Result` of bytes read
-        std::io::stdin().read_line(&mut buffer)?;
-        let input = buffer.trim_end().to_lowercase();
-        match input.as_str() {
-            "y" | "yes" => return Ok(true),
-            "n" | "no" => return Ok(false),
-            _ => continue,
+        if std::io::stdin().read_line(&mut buffer)? == 0 {
+            return Err(no_one_to_ask());
+        }
+        if let Some(confirmed) = confirm_answer(&buffer, default) {
+            return Ok(confirmed);
+        }
+    }
+}
+
+/// As [ask_confirm], reading answers from lines kept across questions.
+/// A question dropped unanswered (ex timed out) doesn't swallow the answer to the next one.
+pub async fn ask_confirm_from<R>(
+    lines: &mut Lines<R>,
+    query: &str,
+    default: Option<bool>,
+) -> Result<bool, std::io::Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    loop {
+        println!("{} {}", query, options_text(default));
+        let Some(line) = lines.next_line().await? else {
+            return Err(no_one_to_ask());
         };
+        if let Some(confirmed) = confirm_answer(&line, default) {
+            return Ok(confirmed);
+        }
     }
 }
 
+fn options_text(default: Option<bool>) -> &'static str {
+    match default {
+        Some(true) => "(Y/n)",
+        Some(false) => "(y/N)",
+        None => "(y/n)",
+    }
+}
+
+/// Yes or no answered, if understood
+fn confirm_answer(answer: &str, default: Option<bool>) -> Option<bool> {
+    match (answer.trim().to_lowercase().as_str(), default) {
+        ("y" | "yes", _) => Some(true),
+        ("n" | "no", _) => Some(false),
+        ("", default) => default,
+        _ => None,
+    }
+}
+
+fn no_one_to_ask() -> std::io::Error {
+    IoError::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "stdin closed, no one to ask",
+    )
+}
+
 /// returns relative path cleaned of any dots (./ ../ ../../) or leading root (/)
 pub fn sanitize_relative_path(file_path: &str) -> PathBuf {
-    // process filename path, first clean (using path clean) and then remove leading /
+    // process filename path, first clean (using path clean) and then
+    // keep only normal components, dropping leading / and any ../ that clean couldn't resolve
     let clean_path = PathBuf::from(file_path).clean();
-    let relative_path = if clean_path.starts_with("/") {
-        clean_path
-            .strip_prefix("/")
-            .expect("checked for starts, this shouldn't error")
-    } else {
-        &clean_path
+    clean_path
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+/// Path with a number added to the file name, ex `photo (1).jpg`, to tell it from a taken one
+pub fn numbered_path(file_path: &Path, number: u32) -> PathBuf {
+    let stem = file_path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match file_path.extension() {
+        Some(extension) => format!("{stem} ({number}).{}", extension.to_string_lossy()),
+        None => format!("{stem} ({number})"),
     };
-    relative_path.to_path_buf()
+    file_path.with_file_name(file_name)
+}
+
+/// Walk given paths, and describe every file found as per localsend protocol.
+/// Files are hashed, so the peer can verify what it gets, with progress shown on a terminal.
+/// File names are kept relative to the given path's parent,
+/// so the receiver can recreate the directory structure.
+/// Symlinks are followed, but no directory is walked twice (ex: a link to an ancestor).
+/// Modified and accessed times go along, for the receiver to keep.
+pub fn collect_files(paths: &[PathBuf]) -> Result<HashMap<FileId, (FileInfo, PathBuf)>, IoError> {
+    let mut found = Vec::new();
+    let mut visited_dirs = HashSet::new();
+    for path in paths {
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        let mut pending = vec![path.clone()];
+        while let Some(path) = pending.pop() {
+            let metadata = std::fs::metadata(&path)?;
+            if metadata.is_dir() {
+                if !visited_dirs.insert(std::fs::canonicalize(&path)?) {
+                    continue;
+                }
+                for entry in std::fs::read_dir(&path)? {
+                    pending.push(entry?.path());
+                }
+                continue;
+            }
+            let file_name = path
+                .strip_prefix(base_dir)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .to_string();
+            found.push((path, file_name, metadata));
+        }
+    }
+    let show_progress = std::io::stderr().is_terminal() && !found.is_empty();
+    let files_count = found.len();
+    let mut local_files = HashMap::new();
+    for (index, (path, file_name, metadata)) in found.into_iter().enumerate() {
+        if show_progress {
+            eprint!("\rHashing files {}/{files_count}", index + 1);
+        }
+        let file_type = mime_guess::from_path(&path)
+            .first_or_octet_stream()
+            .to_string();
+        let file_id: FileId = uuid::Uuid::new_v4().to_string().into();
+        let file_info = FileInfo::new(
+            file_id.clone(),
+            file_name,
+            metadata.len(),
+            file_type,
+            Some(sha256_file(&path)?),
+            None,
+            Some(FileMeta::from_times(
+                metadata.modified().ok().map(Into::into),
+                metadata.accessed().ok().map(Into::into),
+            )),
+        );
+        local_files.insert(file_id, (file_info, path));
+    }
+    if show_progress {
+        eprintln!();
+    }
+    Ok(local_files)
+}
+
+/// Times to give a received file, those the sender had for it (if any)
+pub fn file_times(file_info: &FileInfo) -> FileTimes {
+    let mut file_times = FileTimes::new();
+    let Some(metadata) = file_info.metadata() else {
+        return file_times;
+    };
+    if let Some(modified) = metadata.modified_time() {
+        file_times = file_times.set_modified(modified.into());
+    }
+    if let Some(accessed) = metadata.accessed_time() {
+        file_times = file_times.set_accessed(accessed.into());
+    }
+    file_times
+}
+
+/// Run request, retrying with growing pauses while peer replies too many requests (429)
+pub async fn retry_too_many_requests<T, F, Fut>(mut request: F) -> Result<T, ClientError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ClientError>>,
+{
+    let mut pause = Duration::from_secs(2);
+    for _retry in 0..3 {
+        match request().await {
+            Err(error) if error.is_too_many_requests() => {
+                println!("{error}, retrying in {}s", pause.as_secs());
+                tokio::time::sleep(pause).await;
+                pause *= 2;
+            }
+            result => return result,
+        }
+    }
+    request().await
+}
+
+/// Scope (interface index) of a link-local ipv6 address, without which it can't be reached back
+pub fn link_local_scope(address: SocketAddr) -> Option<u32> {
+    match address {
+        SocketAddr::V6(address)
+            if address.ip().is_unicast_link_local() && address.scope_id() != 0 =>
+        {
+            Some(address.scope_id())
+        }
+        _ => None,
+    }
+}
+
+/// Base url of peer api, ex `https://192.168.1.2:53317` or `https://[fd00::2]:53317`
+pub fn peer_url(protocol: Protocol, address: IpAddr, port: u16) -> Url {
+    let scheme = protocol.to_string().to_lowercase();
+    // Socket address display brackets ipv6 literals, as urls need
+    Url::parse(&format!("{scheme}://{}", SocketAddr::new(address, port)))
+        .expect("ip address and port should be parseable")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use std::path::Path;
+
+    use super::{collect_files, confirm_answer, link_local_scope, numbered_path};
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loop_walked_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = dir.path().join("shared");
+        std::fs::create_dir_all(shared.join("sub")).unwrap();
+        std::fs::write(shared.join("sub/a.txt"), b"a").unwrap();
+        std::os::unix::fs::symlink(&shared, shared.join("sub/loop")).unwrap();
+        let files = collect_files(&[shared]).unwrap();
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn numbered_path_keeps_extension() {
+        assert_eq!(
+            numbered_path(Path::new("dir/photo.jpg"), 1),
+            Path::new("dir/photo (1).jpg")
+        );
+        assert_eq!(
+            numbered_path(Path::new("app.tar.gz"), 2),
+            Path::new("app.tar (2).gz")
+        );
+        assert_eq!(
+            numbered_path(Path::new("README"), 3),
+            Path::new("README (3)")
+        );
+    }
+
+    #[test]
+    fn empty_answer_picks_default() {
+        assert_eq!(confirm_answer("\n", Some(false)), Some(false));
+        assert_eq!(confirm_answer("", Some(true)), Some(true));
+        assert_eq!(confirm_answer("", None), None);
+        assert_eq!(confirm_answer(" Yes\n", Some(false)), Some(true));
+        assert_eq!(confirm_answer("n", Some(true)), Some(false));
+        assert_eq!(confirm_answer("maybe", Some(true)), None);
+    }
+
+    #[test]
+    fn link_local_origin_keeps_scope() {
+        let origin: SocketAddr = "[fe80::1%3]:53317".parse().unwrap();
+        assert_eq!(link_local_scope(origin), Some(3));
+        // Routable addresses need no scope, even if the socket reports one
+        let origin: SocketAddr = "[fd00::2%3]:53317".parse().unwrap();
+        assert_eq!(link_local_scope(origin), None);
+        let origin: SocketAddr = "192.168.1.2:53317".parse().unwrap();
+        assert_eq!(link_local_scope(origin), None);
+    }
 }
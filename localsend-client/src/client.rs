@@ -0,0 +1,543 @@
+use std::{
+    io::SeekFrom,
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+};
+
+use localsend_lib_types::{
+    api::{
+        CancelParams, DownloadParams, Negotiated, Pin, PrepareDownloadParams, PrepareUploadParams,
+        Route, UploadParams,
+    },
+    messages::{
+        common_fields::{FileId, FileInfo, FileUploadToken, Fingerprint, SessionId, Version},
+        discover::{LegacyRegister, LegacyRegisterResponse},
+        download::PrepareDownloadResponse,
+        upload::{PrepareUploadRequest, PrepareUploadResponse},
+        v1,
+    },
+};
+use reqwest::{
+    header::{CONTENT_RANGE, CONTENT_TYPE, RANGE},
+    Body, Client, Response, StatusCode,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+};
+use tokio_util::io::ReaderStream;
+use url::{Host, Url};
+
+use crate::{
+    errors::ClientError,
+    hash::hex_digest,
+    tls::{client_config, SeenCertificate},
+};
+
+/// User agent, unless the application gives its own (see [LocalSendClient::with_user_agent])
+pub const FOX_USER_AGENT: &str = concat!(
+    "Mozilla/5.0 (X11; Linux;) Gecko/20100101 ",
+    env!("CARGO_PKG_NAME"),
+    "/",
+    env!("CARGO_PKG_VERSION"),
+);
+
+/// Client for the http api of a single localsend peer.
+/// Routes (v1 or v2) are picked by the peer's protocol version, v2 unless told otherwise.
+#[derive(Debug, Clone)]
+pub struct LocalSendClient {
+    client: Client,
+    base_url: Url,
+    fingerprint: Option<Fingerprint>,
+    /// Certificate peer presented first, to be presented on every connection after
+    seen_certificate: SeenCertificate,
+    user_agent: String,
+    /// Made-up host of base url, and the scoped address it stands for
+    zone: Option<(String, SocketAddr)>,
+    negotiated: Negotiated,
+}
+
+impl LocalSendClient {
+    /// Client for peer at base url, ex `https://192.168.1.2:53317`.
+    /// Any self-signed certificate is accepted from https peers at first, the same one after that.
+    /// [LocalSendClient::register] checks it against the fingerprint the peer replies.
+    pub fn new(base_url: Url) -> Result<Self, ClientError> {
+        Self::with_fingerprint(base_url, None)
+    }
+
+    /// Client for https peer at base url, whose certificate must match fingerprint.
+    /// Use this when the fingerprint was learned beforehand, ex from discovery.
+    pub fn pinned(base_url: Url, fingerprint: Fingerprint) -> Result<Self, ClientError> {
+        Self::with_fingerprint(base_url, Some(fingerprint))
+    }
+
+    fn with_fingerprint(
+        base_url: Url,
+        fingerprint: Option<Fingerprint>,
+    ) -> Result<Self, ClientError> {
+        let seen_certificate = SeenCertificate::default();
+        Ok(LocalSendClient {
+            client: http_client(
+                fingerprint.clone(),
+                seen_certificate.clone(),
+                FOX_USER_AGENT,
+                None,
+            )?,
+            base_url,
+            fingerprint,
+            seen_certificate,
+            user_agent: FOX_USER_AGENT.to_owned(),
+            zone: None,
+            negotiated: Negotiated::with_peer(Version::default()),
+        })
+    }
+
+    /// Introduce ourselves to peer with given user agent, ex the application's name and version
+    pub fn with_user_agent(self, user_agent: &str) -> Result<Self, ClientError> {
+        LocalSendClient {
+            user_agent: user_agent.to_owned(),
+            ..self
+        }
+        .rebuilt()
+    }
+
+    /// Reach ipv6 peer through given scope (interface index), as link-local addresses need.
+    /// Urls can't carry the scope, so the base url gets a made-up host resolving to the scoped address.
+    /// Peers not at an ipv6 address are left as is.
+    pub fn with_scope_id(self, scope_id: u32) -> Result<Self, ClientError> {
+        let Some(Host::Ipv6(address)) = self.base_url.host() else {
+            return Ok(self);
+        };
+        let host = scoped_host(address, scope_id);
+        let mut base_url = self.base_url.clone();
+        base_url
+            .set_host(Some(&host))
+            .expect("made-up host should be a valid domain");
+        let scoped_address = SocketAddr::V6(SocketAddrV6::new(address, 0, 0, scope_id));
+        LocalSendClient {
+            base_url,
+            zone: Some((host, scoped_address)),
+            ..self
+        }
+        .rebuilt()
+    }
+
+    fn rebuilt(self) -> Result<Self, ClientError> {
+        Ok(LocalSendClient {
+            client: http_client(
+                self.fingerprint.clone(),
+                self.seen_certificate.clone(),
+                &self.user_agent,
+                self.zone.as_ref(),
+            )?,
+            ..self
+        })
+    }
+
+    /// Talk to peer as its advertised protocol version allows
+    pub fn with_version(self, version: Version) -> Self {
+        LocalSendClient {
+            negotiated: Negotiated::with_peer(version),
+            ..self
+        }
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Protocol version we talk to peer with
+    pub fn version(&self) -> Version {
+        *self.negotiated.version()
+    }
+
+    fn endpoint(&self, path: &str) -> Url {
+        self.base_url
+            .join(path)
+            .expect("prechecked path shouldn't error")
+    }
+
+    /// Path of route, if peer's version has it
+    fn route(&self, route: Route) -> Result<&'static str, ClientError> {
+        self.negotiated
+            .route(route)
+            .ok_or(ClientError::Unsupported(route, self.version()))
+    }
+
+    async fn post_json<T: Serialize>(
+        &self,
+        path: &str,
+        query: Option<&impl Serialize>,
+        body: &T,
+    ) -> Result<Response, ClientError> {
+        let mut request = self
+            .client
+            .post(self.endpoint(path))
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(body)?);
+        if let Some(query) = query {
+            request = request.query(query);
+        }
+        Ok(request.send().await?)
+    }
+
+    /// Ask sender for files info, and a session to download them in.
+    /// (Download API, 5.2)
+    pub async fn prepare_download(
+        &self,
+        pin: Option<&str>,
+    ) -> Result<PrepareDownloadResponse, ClientError> {
+        let params = PrepareDownloadParams::new(pin.map(|pin| Pin::new(pin.into())), None);
+        let response = self
+            .client
+            .post(self.endpoint(self.route(Route::PrepareDownload)?))
+            .query(&params)
+            .send()
+            .await?;
+        if response.status() != StatusCode::OK {
+            let (status_code, body) = status_and_body(response).await;
+            return Err(ClientError::PrepareDownload(status_code.into(), body));
+        }
+        Ok(serde_json::from_str(&response.text().await?)?)
+    }
+
+    /// Download a file of the session, writing it into writer.
+    /// If sender advertised a sha256 for the file, contents are verified against it.
+    /// Returns the number of bytes written.
+    /// (Download API, 5.3)
+    pub async fn download<W>(
+        &self,
+        session_id: &SessionId,
+        file_info: &FileInfo,
+        writer: &mut W,
+    ) -> Result<u64, ClientError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let response = self.request_download(session_id, file_info, None).await?;
+        write_verified(response, Sha256::new(), file_info, writer).await
+    }
+
+    /// Continue downloading a file into the partial download in file.
+    /// Only the missing tail is requested (http range), and appended.
+    /// Falls back to downloading the whole file again, if sender ignores the range.
+    /// Verification covers the bytes already present too, a complete file is only verified.
+    /// Returns the number of bytes written (this time).
+    pub async fn resume_download(
+        &self,
+        session_id: &SessionId,
+        file_info: &FileInfo,
+        file: &mut File,
+    ) -> Result<u64, ClientError> {
+        let mut hasher = Sha256::new();
+        let mut present = 0;
+        let mut buf = vec![0; 64 * 1024];
+        file.seek(SeekFrom::Start(0)).await?;
+        loop {
+            let read = file.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+            present += read as u64;
+        }
+        if present > 0 && present == file_info.size() && verify(hasher.clone(), file_info).is_ok() {
+            return Ok(0);
+        }
+        if present > 0 && present < file_info.size() {
+            let response = self
+                .request_download(session_id, file_info, Some(present))
+                .await?;
+            if response.status() == StatusCode::PARTIAL_CONTENT
+                && range_start(&response) == Some(present)
+            {
+                return write_verified(response, hasher, file_info, file).await;
+            }
+            if response.status() == StatusCode::OK {
+                // Range ignored, whole file it is
+                file.set_len(0).await?;
+                file.seek(SeekFrom::Start(0)).await?;
+                return write_verified(response, Sha256::new(), file_info, file).await;
+            }
+            // Some other range than asked for, don't try to stitch it
+        }
+        file.set_len(0).await?;
+        file.seek(SeekFrom::Start(0)).await?;
+        self.download(session_id, file_info, file).await
+    }
+
+    /// Send download request, from given offset if any.
+    /// Errors unless reply is the file (200), or a range of it (206).
+    async fn request_download(
+        &self,
+        session_id: &SessionId,
+        file_info: &FileInfo,
+        offset: Option<u64>,
+    ) -> Result<Response, ClientError> {
+        let params = DownloadParams::new(session_id.clone(), file_info.id().clone());
+        let mut request = self
+            .client
+            .get(self.endpoint(self.route(Route::Download)?))
+            .query(&params);
+        if let Some(offset) = offset {
+            request = request.header(RANGE, format!("bytes={offset}-"));
+        }
+        let response = request.send().await?;
+        if !matches!(
+            response.status(),
+            StatusCode::OK | StatusCode::PARTIAL_CONTENT
+        ) {
+            let (status_code, body) = status_and_body(response).await;
+            return Err(ClientError::Status(status_code, body));
+        }
+        Ok(response)
+    }
+
+    /// Send files info to receiver, and get tokens for the files it accepts.
+    /// No file transfer needed (204) means none accepted.
+    /// v1 peers get a send request instead, and reply with no session (left empty).
+    /// (Upload API, 4.1)
+    pub async fn prepare_upload(
+        &self,
+        request: &PrepareUploadRequest,
+        pin: Option<&str>,
+    ) -> Result<PrepareUploadResponse, ClientError> {
+        let params = pin.map(|pin| PrepareUploadParams::new(Pin::new(pin.into())));
+        let path = self.route(Route::PrepareUpload)?;
+        let response = if self.negotiated.has_sessions() {
+            self.post_json(path, params.as_ref(), request).await?
+        } else {
+            let send_request = v1::SendRequest::from(request);
+            self.post_json(path, params.as_ref(), &send_request).await?
+        };
+        if response.status() == StatusCode::NO_CONTENT {
+            // Receiver has all the files already, nothing to upload (and no session)
+            let no_files = std::iter::empty().collect();
+            return Ok(PrepareUploadResponse::new(
+                SessionId::new(String::new()),
+                no_files,
+            ));
+        }
+        if response.status() != StatusCode::OK {
+            let (status_code, body) = status_and_body(response).await;
+            return Err(ClientError::PrepareUpload(status_code.into(), body));
+        }
+        let body = response.text().await?;
+        if self.negotiated.has_sessions() {
+            Ok(serde_json::from_str(&body)?)
+        } else {
+            Ok(serde_json::from_str::<v1::SendResponse>(&body)?.into())
+        }
+    }
+
+    /// Upload a file accepted by receiver, reading it from reader.
+    /// (Upload API, 4.2)
+    pub async fn upload<R>(
+        &self,
+        session_id: &SessionId,
+        file_id: &FileId,
+        token: &FileUploadToken,
+        reader: R,
+    ) -> Result<(), ClientError>
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        let mut request = self.client.post(self.endpoint(self.route(Route::Upload)?));
+        request = if self.negotiated.has_sessions() {
+            request.query(&UploadParams::new(
+                session_id.clone(),
+                file_id.clone(),
+                token.clone(),
+            ))
+        } else {
+            request.query(&v1::SendParams::new(file_id.clone(), token.clone()))
+        };
+        let response = request
+            .body(Body::wrap_stream(ReaderStream::new(reader)))
+            .send()
+            .await?;
+        if response.status() != StatusCode::OK {
+            let (status_code, body) = status_and_body(response).await;
+            return Err(ClientError::Upload(status_code.into(), body));
+        }
+        Ok(())
+    }
+
+    /// Cancel an upload session
+    /// (Upload API, 4.3)
+    pub async fn cancel(&self, session_id: &SessionId) -> Result<(), ClientError> {
+        let mut request = self.client.post(self.endpoint(self.route(Route::Cancel)?));
+        // Without sessions, v1 cancels whatever is ongoing
+        if self.negotiated.has_sessions() {
+            request = request.query(&CancelParams::new(session_id.clone()));
+        }
+        let response = request.send().await?;
+        if response.status() != StatusCode::OK {
+            let (status_code, body) = status_and_body(response).await;
+            return Err(ClientError::Status(status_code, body));
+        }
+        Ok(())
+    }
+
+    /// Fingerprint of the certificate peer presented (https), once connected
+    pub fn peer_fingerprint(&self) -> Option<Fingerprint> {
+        self.seen_certificate
+            .lock()
+            .expect("seen certificate lock poisoned")
+            .clone()
+    }
+
+    /// Register ourselves with peer, and learn about it in return.
+    /// From v2 on, peer's certificate must hash to the fingerprint it replies (https).
+    /// A client not pinned beforehand is pinned to that certificate from then on.
+    /// (Discovery, 3.1 response / 3.2 legacy http)
+    pub async fn register(
+        &self,
+        register: &LegacyRegister,
+    ) -> Result<LegacyRegisterResponse, ClientError> {
+        let path = self.route(Route::Register)?;
+        let response = if self.negotiated.has_sessions() {
+            self.post_json(path, None::<&()>, register).await?
+        } else {
+            let v1_register = v1::Register::from(register.device_info().clone());
+            self.post_json(path, None::<&()>, &v1_register).await?
+        };
+        if response.status() != StatusCode::OK {
+            let (status_code, body) = status_and_body(response).await;
+            return Err(ClientError::Status(status_code, body));
+        }
+        let body = response.text().await?;
+        let register_response: LegacyRegisterResponse = if self.negotiated.has_sessions() {
+            serde_json::from_str(&body)?
+        } else {
+            serde_json::from_str::<v1::Register>(&body)?.into()
+        };
+        // Before v2, fingerprints needn't be certificate hashes
+        if *register_response.version() >= Version::V2_0 {
+            let claimed = register_response.device_info().fingerprint();
+            if let Some(certificate) = self.peer_fingerprint() {
+                if !claimed.matches(&certificate) {
+                    return Err(ClientError::FingerprintMismatch {
+                        claimed: claimed.clone(),
+                        certificate,
+                    });
+                }
+            }
+        }
+        Ok(register_response)
+    }
+}
+
+fn http_client(
+    fingerprint: Option<Fingerprint>,
+    seen_certificate: SeenCertificate,
+    user_agent: &str,
+    zone: Option<&(String, SocketAddr)>,
+) -> Result<Client, ClientError> {
+    let mut builder = Client::builder()
+        .user_agent(user_agent)
+        .use_preconfigured_tls(client_config(fingerprint, seen_certificate));
+    if let Some((host, scoped_address)) = zone {
+        builder = builder.resolve(host, *scoped_address);
+    }
+    Ok(builder.build()?)
+}
+
+/// Host name standing for ipv6 address within scope, ex `fe80-0-0-0-0-0-0-1.s2.invalid`
+fn scoped_host(address: Ipv6Addr, scope_id: u32) -> String {
+    let segments: Vec<String> = address
+        .segments()
+        .iter()
+        .map(|segment| format!("{segment:x}"))
+        .collect();
+    format!("{}.s{scope_id}.invalid", segments.join("-"))
+}
+
+/// Write reply body into writer, verifying the whole file hashes to advertised sha256.
+/// Hasher may already have consumed the part of file present before this reply.
+async fn write_verified<W>(
+    mut response: Response,
+    mut hasher: Sha256,
+    file_info: &FileInfo,
+    writer: &mut W,
+) -> Result<u64, ClientError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut written = 0;
+    while let Some(chunk) = response.chunk().await? {
+        hasher.update(&chunk);
+        writer.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+    }
+    writer.flush().await?;
+    verify(hasher, file_info)?;
+    Ok(written)
+}
+
+/// Check what hasher consumed against the sha256 advertised for the file, if any
+fn verify(hasher: Sha256, file_info: &FileInfo) -> Result<(), ClientError> {
+    if let Some(expected) = file_info.sha_256() {
+        let actual = hex_digest(hasher);
+        if !expected.matches(&actual) {
+            return Err(ClientError::Verification {
+                expected: expected.clone(),
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// First byte of a partial reply, from its `Content-Range: bytes start-end/size` header
+fn range_start(response: &Response) -> Option<u64> {
+    content_range_start(response.headers().get(CONTENT_RANGE)?.to_str().ok()?)
+}
+
+fn content_range_start(content_range: &str) -> Option<u64> {
+    let (start, _rest) = content_range.strip_prefix("bytes ")?.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+/// Status code and body of an unsuccessful reply
+async fn status_and_body(response: Response) -> (u16, String) {
+    let status_code = response.status().as_u16();
+    (status_code, response.text().await.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::{content_range_start, LocalSendClient};
+
+    #[test]
+    fn content_range_start_parse() {
+        assert_eq!(content_range_start("bytes 100-199/200"), Some(100));
+        assert_eq!(content_range_start("bytes 0-0/1"), Some(0));
+        assert_eq!(content_range_start("bytes */200"), None);
+        assert_eq!(content_range_start("items 1-2/3"), None);
+    }
+
+    #[test]
+    fn scope_kept_in_made_up_host() {
+        let client = LocalSendClient::new(Url::parse("https://[fe80::1]:53317").unwrap())
+            .unwrap()
+            .with_scope_id(3)
+            .unwrap();
+        assert_eq!(
+            client.base_url().as_str(),
+            "https://fe80-0-0-0-0-0-0-1.s3.invalid:53317/"
+        );
+        let (_host, scoped_address) = client.zone.unwrap();
+        assert_eq!(scoped_address, "[fe80::1%3]:0".parse().unwrap());
+        // Only ipv6 addresses have a scope
+        let client = LocalSendClient::new(Url::parse("https://192.168.1.2:53317").unwrap())
+            .unwrap()
+            .with_scope_id(3)
+            .unwrap();
+        assert_eq!(client.base_url().as_str(), "https://192.168.1.2:53317/");
+        assert!(client.zone.is_none());
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt::Display;
+
+use localsend_lib_types::{
+    api::Route,
+    errors::{PrepareDownloadError, PrepareUploadError, UploadError},
+    messages::common_fields::{Fingerprint, Version, SHA256},
+};
+use thiserror::Error;
+
+/// Errors replied by the peer carry the reply body too, which may explain further.
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Could not reach peer: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Unexpected reply from peer: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("{}", with_reason(.0, .1))]
+    PrepareDownload(PrepareDownloadError, String),
+    #[error("{}", with_reason(.0, .1))]
+    PrepareUpload(PrepareUploadError, String),
+    #[error("{}", with_reason(.0, .1))]
+    Upload(UploadError, String),
+    /// Downloaded contents don't hash to what the sender advertised
+    #[error("Verification failed, expected sha256 {expected} but got {actual}")]
+    Verification { expected: SHA256, actual: SHA256 },
+    /// Peer's certificate doesn't hash to the fingerprint it claims
+    #[error("Peer claims fingerprint {claimed}, but its certificate has {certificate}")]
+    FingerprintMismatch {
+        claimed: Fingerprint,
+        certificate: Fingerprint,
+    },
+    /// Peer's protocol version has no such endpoint, ex download api before 2.1
+    #[error("Peer speaks protocol {1}, which has no {0:?} api")]
+    Unsupported(Route, Version),
+    /// Status not documented for the endpoint
+    #[error("{}", with_reason(&format!("Peer replied with status {}", .0), .1))]
+    Status(u16, String),
+}
+
+impl ClientError {
+    /// Peer requires a pin, or the one given was wrong (401)
+    pub fn is_pin_required(&self) -> bool {
+        matches!(
+            self,
+            ClientError::PrepareDownload(PrepareDownloadError::Http401, _)
+                | ClientError::PrepareUpload(PrepareUploadError::Http401, _)
+        )
+    }
+
+    /// Peer is rate limiting us (429). The request may be retried after a while.
+    pub fn is_too_many_requests(&self) -> bool {
+        matches!(
+            self,
+            ClientError::PrepareDownload(PrepareDownloadError::Http429, _)
+                | ClientError::PrepareUpload(PrepareUploadError::Http429, _)
+        )
+    }
+}
+
+/// Describe error, followed by first line of reply body if that adds anything
+fn with_reason(error: &impl Display, body: &str) -> String {
+    let error = error.to_string();
+    match body.lines().next().map(str::trim) {
+        Some(reason) if !reason.is_empty() && reason != error => format!("{error} ({reason})"),
+        _ => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::with_reason;
+
+    #[test]
+    fn reason_only_when_it_adds_something() {
+        assert_eq!(with_reason(&"Rejected", ""), "Rejected");
+        assert_eq!(with_reason(&"Rejected", "Rejected\nmore"), "Rejected");
+        assert_eq!(
+            with_reason(&"Rejected", "  Not now \nmore"),
+            "Rejected (Not now)"
+        );
+    }
+}
@@ -0,0 +1,18 @@
+//! SHA-256 digests of files, in the form advertised in `FileInfo`
+use std::path::Path;
+
+use localsend_lib_types::messages::common_fields::SHA256;
+use sha2::{Digest, Sha256};
+use std::io::Error as IoError;
+
+/// Lowercase hex digest of everything hashed so far
+pub(crate) fn hex_digest(hasher: Sha256) -> SHA256 {
+    format!("{:x}", hasher.finalize()).into()
+}
+
+/// Hash file at path, reading it whole
+pub fn sha256_file(path: &Path) -> Result<SHA256, IoError> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hex_digest(hasher))
+}
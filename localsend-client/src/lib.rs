@@ -0,0 +1,7 @@
+//! Async client for the localsend protocol http api
+mod client;
+pub mod errors;
+pub mod hash;
+pub mod tls;
+
+pub use client::LocalSendClient;
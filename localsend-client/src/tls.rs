@@ -0,0 +1,142 @@
+//! Tls for the https api. Localsend peers use self-signed certificates,
+//! identified by their fingerprint (SHA-256 of the certificate) instead of a CA.
+use std::sync::{Arc, Mutex};
+
+use localsend_lib_types::messages::common_fields::Fingerprint;
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, Error as TlsError, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+
+use crate::hash::hex_digest;
+
+/// Fingerprint of a (der encoded) certificate, as announced by its owner
+pub fn certificate_fingerprint(certificate_der: &[u8]) -> Fingerprint {
+    let mut hasher = Sha256::new();
+    hasher.update(certificate_der);
+    Fingerprint::new(hex_digest(hasher).to_string())
+}
+
+/// Fingerprint of the certificate a peer presented first, shared by the clients of that peer
+pub(crate) type SeenCertificate = Arc<Mutex<Option<Fingerprint>>>;
+
+/// Client config accepting self-signed peers.
+/// If fingerprint is known, peer certificate must match it.
+/// Otherwise the first certificate presented is recorded in seen, and must be presented again later.
+pub(crate) fn client_config(
+    fingerprint: Option<Fingerprint>,
+    seen: SeenCertificate,
+) -> ClientConfig {
+    let provider = Arc::new(ring::default_provider());
+    ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("ring provider should support default protocol versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(FingerprintVerifier {
+            fingerprint,
+            seen,
+            provider,
+        }))
+        .with_no_client_auth()
+}
+
+/// Verifies peer certificate by fingerprint, rather than by chain of trust.
+/// Handshake signatures are still verified, so peer must hold the certificate key.
+#[derive(Debug)]
+struct FingerprintVerifier {
+    fingerprint: Option<Fingerprint>,
+    seen: SeenCertificate,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        let presented = certificate_fingerprint(end_entity);
+        let mut seen = self.seen.lock().expect("seen certificate lock poisoned");
+        let expected = self.fingerprint.as_ref().or(seen.as_ref());
+        if expected.is_some_and(|expected| !presented.matches(expected)) {
+            return Err(TlsError::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        *seen = Some(presented);
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::{client::danger::ServerCertVerifier, pki_types::ServerName};
+
+    use super::*;
+
+    #[test]
+    fn unpinned_verifier_sticks_to_first_certificate() {
+        let verifier = FingerprintVerifier {
+            fingerprint: None,
+            seen: SeenCertificate::default(),
+            provider: Arc::new(ring::default_provider()),
+        };
+        let server_name = ServerName::try_from("peer.invalid").unwrap();
+        let verify = |certificate: &'static [u8]| {
+            verifier.verify_server_cert(
+                &CertificateDer::from(certificate),
+                &[],
+                &server_name,
+                &[],
+                UnixTime::now(),
+            )
+        };
+        assert!(verify(b"first certificate").is_ok());
+        assert!(verify(b"first certificate").is_ok());
+        assert!(verify(b"other certificate").is_err());
+        assert_eq!(
+            verifier.seen.lock().unwrap().as_ref(),
+            Some(&certificate_fingerprint(b"first certificate"))
+        );
+    }
+}
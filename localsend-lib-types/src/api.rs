@@ -1,33 +1,147 @@
-//! Params for different endpoints
-use crate::messages::common_fields::{FileId, FileUploadToken, SessionId};
-use derive_more::derive::Constructor;
+//! Params for different endpoints, and which endpoints a peer has
+use crate::messages::common_fields::{FileId, FileUploadToken, SessionId, Version};
+use derive_getters::Getters;
+use derive_more::derive::{AsRef, Constructor};
 use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
 
 /// Private pin to authenticate
-#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, AsRef)]
 pub struct Pin(String);
 
-#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq)]
+/// Both optional. The downloader may pass an earlier session id, to continue in the same session.
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters)]
+#[serde(rename_all = "camelCase")]
 pub struct PrepareDownloadParams {
-    pin: Pin,
+    pin: Option<Pin>,
+    session_id: Option<SessionId>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters)]
 #[serde(rename_all = "camelCase")]
 pub struct DownloadParams {
     session_id: SessionId,
     file_id: FileId,
 }
 
-#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq)]
+/// Only sent when the receiver requires a pin
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters)]
 pub struct PrepareUploadParams {
     pin: Pin,
 }
 
-#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters)]
 #[serde(rename_all = "camelCase")]
 pub struct UploadParams {
     session_id: SessionId,
     file_id: FileId,
     token: FileUploadToken,
 }
+
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelParams {
+    session_id: SessionId,
+}
+
+/// Endpoints, as named in protocol v2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Register,
+    PrepareUpload,
+    Upload,
+    Cancel,
+    PrepareDownload,
+    Download,
+}
+
+/// What to use talking to a peer: the lower of its advertised version and ours.
+/// Decides the route family (v1 / v2) and which features are there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Getters)]
+pub struct Negotiated {
+    version: Version,
+}
+
+impl Negotiated {
+    pub fn new(ours: Version, peer: Version) -> Self {
+        Negotiated {
+            version: ours.min(peer),
+        }
+    }
+
+    /// Going by the peer's version, against the one we speak
+    pub fn with_peer(peer: Version) -> Self {
+        Negotiated::new(Version::default(), peer)
+    }
+
+    /// Path of endpoint, if the peer has it
+    pub fn route(&self, route: Route) -> Option<&'static str> {
+        let path = if self.has_sessions() {
+            match route {
+                Route::Register => "/api/localsend/v2/register",
+                Route::PrepareUpload => "/api/localsend/v2/prepare-upload",
+                Route::Upload => "/api/localsend/v2/upload",
+                Route::Cancel => "/api/localsend/v2/cancel",
+                Route::PrepareDownload if self.has_download_api() => {
+                    "/api/localsend/v2/prepare-download"
+                }
+                Route::Download if self.has_download_api() => "/api/localsend/v2/download",
+                Route::PrepareDownload | Route::Download => return None,
+            }
+        } else {
+            match route {
+                Route::Register => "/api/localsend/v1/register",
+                Route::PrepareUpload => "/api/localsend/v1/send-request",
+                Route::Upload => "/api/localsend/v1/send",
+                Route::Cancel => "/api/localsend/v1/cancel",
+                Route::PrepareDownload | Route::Download => return None,
+            }
+        };
+        Some(path)
+    }
+
+    /// Uploads go in sessions (with session id), from 2.0. Before, tokens alone sufficed.
+    pub fn has_sessions(&self) -> bool {
+        self.version >= Version::V2_0
+    }
+
+    /// Download api (prepare-download / download), from 2.1.
+    /// The peer still has to advertise it (`download: true`).
+    pub fn has_download_api(&self) -> bool {
+        self.version >= Version::V2_1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Negotiated, Route};
+    use crate::messages::common_fields::Version;
+
+    #[test]
+    fn negotiate_routes_by_version() {
+        let old_peer = Negotiated::with_peer(Version::V1_0);
+        assert_eq!(*old_peer.version(), Version::V1_0);
+        assert!(!old_peer.has_sessions());
+        assert_eq!(
+            old_peer.route(Route::PrepareUpload),
+            Some("/api/localsend/v1/send-request")
+        );
+        assert_eq!(old_peer.route(Route::Download), None);
+
+        let v2_0_peer = Negotiated::with_peer(Version::V2_0);
+        assert_eq!(
+            v2_0_peer.route(Route::Upload),
+            Some("/api/localsend/v2/upload")
+        );
+        assert_eq!(v2_0_peer.route(Route::PrepareDownload), None);
+
+        // Newer peers get what we know of
+        let newer_peer = Negotiated::with_peer(Version::new(2, 5));
+        assert_eq!(*newer_peer.version(), Version::V2_1);
+        assert_eq!(
+            newer_peer.route(Route::PrepareDownload),
+            Some("/api/localsend/v2/prepare-download")
+        );
+    }
+}
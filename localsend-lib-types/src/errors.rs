@@ -1,16 +1,18 @@
-// use std::num::ParseIntError;
+use std::num::ParseIntError;
 
 use thiserror::Error;
 
-// #[derive(Debug, Error)]
-// pub enum VersionDecodeError {
-//     #[error("Version string (x.y): components were not both integers")]
-//     ParseIntError(#[from] ParseIntError),
-//     #[error("Version string (x.y): could not find the dot")]
-//     NoDotFound,
-// }
+#[derive(Debug, Error, PartialEq, Eq, Clone)]
+pub enum VersionDecodeError {
+    #[error("Version string (x.y): components were not both integers")]
+    ParseIntError(#[from] ParseIntError),
+    #[error("Version string (x.y): could not find the dot")]
+    NoDotFound,
+    #[error("Version string (x.y): unexpected character {0:?}")]
+    InvalidCharacter(char),
+}
 
-#[derive(Debug, Error)]
+#[derive(Debug, Error, PartialEq, Eq, Clone, Copy)]
 pub enum PrepareDownloadError {
     #[error("PIN required / Invalid PIN")]
     Http401,
@@ -20,9 +22,11 @@ pub enum PrepareDownloadError {
     Http429,
     #[error("Unknown error by sender")]
     Http500,
+    #[error("Unexpected status {0} from sender")]
+    Other(u16),
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Error, PartialEq, Eq, Clone, Copy)]
 pub enum PrepareUploadError {
     #[error("Finished (No file transfer needed)")]
     Http204,
@@ -38,9 +42,11 @@ pub enum PrepareUploadError {
     Http429,
     #[error("Unknown error by receiver")]
     Http500,
+    #[error("Unexpected status {0} from receiver")]
+    Other(u16),
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Error, PartialEq, Eq, Clone, Copy)]
 pub enum UploadError {
     #[error("Missing parameters")]
     Http400,
@@ -50,4 +56,134 @@ pub enum UploadError {
     Http409,
     #[error("Unknown error by receiver")]
     Http500,
+    #[error("Unexpected status {0} from receiver")]
+    Other(u16),
+}
+
+impl PrepareDownloadError {
+    /// HTTP status code the sender answers with
+    pub fn status_code(&self) -> u16 {
+        match self {
+            PrepareDownloadError::Http401 => 401,
+            PrepareDownloadError::Http403 => 403,
+            PrepareDownloadError::Http429 => 429,
+            PrepareDownloadError::Http500 => 500,
+            PrepareDownloadError::Other(status_code) => *status_code,
+        }
+    }
+}
+
+/// Error for status code of sender's reply
+impl From<u16> for PrepareDownloadError {
+    fn from(status_code: u16) -> Self {
+        match status_code {
+            401 => PrepareDownloadError::Http401,
+            403 => PrepareDownloadError::Http403,
+            429 => PrepareDownloadError::Http429,
+            500 => PrepareDownloadError::Http500,
+            _ => PrepareDownloadError::Other(status_code),
+        }
+    }
+}
+
+impl PrepareUploadError {
+    /// HTTP status code the receiver answers with
+    pub fn status_code(&self) -> u16 {
+        match self {
+            PrepareUploadError::Http204 => 204,
+            PrepareUploadError::Http400 => 400,
+            PrepareUploadError::Http401 => 401,
+            PrepareUploadError::Http403 => 403,
+            PrepareUploadError::Http409 => 409,
+            PrepareUploadError::Http429 => 429,
+            PrepareUploadError::Http500 => 500,
+            PrepareUploadError::Other(status_code) => *status_code,
+        }
+    }
+}
+
+/// Error for status code of receiver's reply
+impl From<u16> for PrepareUploadError {
+    fn from(status_code: u16) -> Self {
+        match status_code {
+            204 => PrepareUploadError::Http204,
+            400 => PrepareUploadError::Http400,
+            401 => PrepareUploadError::Http401,
+            403 => PrepareUploadError::Http403,
+            409 => PrepareUploadError::Http409,
+            429 => PrepareUploadError::Http429,
+            500 => PrepareUploadError::Http500,
+            _ => PrepareUploadError::Other(status_code),
+        }
+    }
+}
+
+impl UploadError {
+    /// HTTP status code the receiver answers with
+    pub fn status_code(&self) -> u16 {
+        match self {
+            UploadError::Http400 => 400,
+            UploadError::Http403 => 403,
+            UploadError::Http409 => 409,
+            UploadError::Http500 => 500,
+            UploadError::Other(status_code) => *status_code,
+        }
+    }
+}
+
+/// Error for status code of receiver's reply
+impl From<u16> for UploadError {
+    fn from(status_code: u16) -> Self {
+        match status_code {
+            400 => UploadError::Http400,
+            403 => UploadError::Http403,
+            409 => UploadError::Http409,
+            500 => UploadError::Http500,
+            _ => UploadError::Other(status_code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PrepareDownloadError, PrepareUploadError, UploadError};
+
+    #[test]
+    fn prepare_download_error_status_roundtrip() {
+        for status_code in [401, 403, 429, 500] {
+            let error = PrepareDownloadError::from(status_code);
+            assert_ne!(error, PrepareDownloadError::Other(status_code));
+            assert_eq!(error.status_code(), status_code);
+        }
+        assert_eq!(
+            PrepareDownloadError::from(418),
+            PrepareDownloadError::Other(418)
+        );
+        assert_eq!(PrepareDownloadError::Other(418).status_code(), 418);
+    }
+
+    #[test]
+    fn prepare_upload_error_status_roundtrip() {
+        for status_code in [204, 400, 401, 403, 409, 429, 500] {
+            let error = PrepareUploadError::from(status_code);
+            assert_ne!(error, PrepareUploadError::Other(status_code));
+            assert_eq!(error.status_code(), status_code);
+        }
+        assert_eq!(
+            PrepareUploadError::from(502),
+            PrepareUploadError::Other(502)
+        );
+        assert_eq!(PrepareUploadError::Other(502).status_code(), 502);
+    }
+
+    #[test]
+    fn upload_error_status_roundtrip() {
+        for status_code in [400, 403, 409, 500] {
+            let error = UploadError::from(status_code);
+            assert_ne!(error, UploadError::Other(status_code));
+            assert_eq!(error.status_code(), status_code);
+        }
+        assert_eq!(UploadError::from(404), UploadError::Other(404));
+        assert_eq!(UploadError::Other(404).status_code(), 404);
+    }
 }
@@ -2,3 +2,4 @@ pub mod common_fields;
 pub mod discover;
 pub mod download;
 pub mod upload;
+pub mod v1;
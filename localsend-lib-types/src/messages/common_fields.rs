@@ -3,9 +3,19 @@ mod common_file_fields;
 
 pub use common_device_fields::*;
 pub use common_file_fields::*;
-use derive_more::derive::{Constructor, From};
+use derive_more::derive::{AsRef, Constructor, Display, From};
 use serde::{Deserialize, Serialize};
 /// SHA256
-#[derive(Debug, Serialize, Deserialize, Constructor, From, PartialEq, Clone)]
+///
+/// Hex encoded digest of file contents
+#[derive(Debug, Display, Serialize, Deserialize, Constructor, From, PartialEq, Clone, AsRef)]
 // pub(super) struct SHA256([u8; 32]);
-pub(super) struct SHA256(String);
+#[from(forward)]
+pub struct SHA256(String);
+
+impl SHA256 {
+    /// Whether both are the same digest. Senders differ in hex case, which means nothing.
+    pub fn matches(&self, other: &SHA256) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
@@ -1,9 +1,14 @@
 //! Fields common to most messages
 
+use std::str::FromStr;
+
 use derive_getters::{Dissolve, Getters};
 use derive_more::derive::{AsRef, Constructor, Display, From};
 // use derive_new::new;
 use serde::{Deserialize, Serialize};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+
+use crate::errors::VersionDecodeError;
 
 /// Alias
 ///
@@ -14,28 +19,59 @@ use serde::{Deserialize, Serialize};
 pub struct Alias(String);
 
 /// Localsend protocol version (major.minor)
-#[derive(Display, Debug, Serialize, Deserialize, Constructor, From, PartialEq, Clone)]
-#[from(forward)]
-pub struct Version(String);
+///
+/// Goes over the wire as a string, ex `"2.1"`. Ordered by major, then minor.
+#[derive(
+    Display,
+    Debug,
+    SerializeDisplay,
+    DeserializeFromStr,
+    Constructor,
+    Getters,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Clone,
+    Copy,
+)]
+#[display("{major}.{minor}")]
+pub struct Version {
+    major: u16,
+    minor: u16,
+}
+
+impl Version {
+    pub const V1_0: Version = Version { major: 1, minor: 0 };
+    pub const V2_0: Version = Version { major: 2, minor: 0 };
+    pub const V2_1: Version = Version { major: 2, minor: 1 };
+}
 
+/// The version we speak
 impl Default for Version {
     fn default() -> Self {
-        Version::new("2.1".to_string())
+        Version::V2_1
+    }
+}
+
+impl FromStr for Version {
+    type Err = VersionDecodeError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // u16 parsing would let signs (+1) through
+        if let Some(character) = s.chars().find(|c| !c.is_ascii_digit() && *c != '.') {
+            return Err(VersionDecodeError::InvalidCharacter(character));
+        }
+        if let Some((major, minor)) = s.split_once('.') {
+            Ok(Version {
+                major: major.parse()?,
+                minor: minor.parse()?,
+            })
+        } else {
+            Err(VersionDecodeError::NoDotFound)
+        }
     }
 }
-// impl FromStr for Version {
-//     type Err = VersionDecodeError;
-//     fn from_str(s: &str) -> Result<Self, Self::Err> {
-//         if let Some((major, minor)) = s.split_once('.') {
-//             return Ok(Version {
-//                 major: major.parse()?,
-//                 minor: minor.parse()?,
-//             });
-//         } else {
-//             Err(VersionDecodeError::NoDotFound)
-//         }
-//     }
-// }
 
 /// Device Model
 ///
@@ -59,11 +95,22 @@ pub enum DeviceType {
 /// Fingerprint
 ///
 /// Unique string identifying the device.
-/// Only used to ignore messages from self.
-#[derive(Display, Debug, Serialize, Deserialize, Constructor, From, PartialEq, Eq, Hash, Clone)]
+/// In https mode, it is the SHA-256 (hex) of the device's tls certificate.
+/// Otherwise only used to ignore messages from self.
+#[derive(
+    Display, Debug, Serialize, Deserialize, Constructor, From, PartialEq, Eq, Hash, Clone, AsRef,
+)]
 #[from(forward)]
 pub struct Fingerprint(String);
 
+impl Fingerprint {
+    /// Whether both are the same fingerprint. Certificate hashes are compared regardless of
+    /// case, some peers print them in upper case hex.
+    pub fn matches(&self, other: &Fingerprint) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
 /// Device Info
 #[derive(Display, Debug, Serialize, Deserialize, Constructor, From, PartialEq, Getters, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -117,6 +164,38 @@ pub enum Protocol {
 /// Session ID
 ///
 /// A shared secret that can be used to authorise upload / download,to / from server
-#[derive(Display, Debug, Serialize, Deserialize, Constructor, From, PartialEq, Clone, AsRef)]
+#[derive(
+    Display, Debug, Serialize, Deserialize, Constructor, From, PartialEq, Eq, Hash, Clone, AsRef,
+)]
 #[from(forward)]
 pub struct SessionId(String);
+
+#[cfg(test)]
+mod tests {
+    use super::Version;
+    use crate::errors::VersionDecodeError;
+
+    #[test]
+    fn version_parse_order_serde() {
+        assert_eq!("2.1".parse(), Ok(Version::V2_1));
+        assert_eq!("10.02".parse(), Ok(Version::new(10, 2)));
+        assert_eq!("2".parse::<Version>(), Err(VersionDecodeError::NoDotFound));
+        assert_eq!(
+            "+2.1".parse::<Version>(),
+            Err(VersionDecodeError::InvalidCharacter('+'))
+        );
+        for invalid in ["2.", ".1", "2.1.0", ""] {
+            assert!(invalid.parse::<Version>().is_err(), "{invalid:?} parsed");
+        }
+        assert!(Version::V1_0 < Version::V2_0);
+        assert!(Version::V2_0 < Version::V2_1);
+        assert!(Version::new(2, 10) > Version::new(2, 9));
+        assert_eq!(Version::default(), Version::V2_1);
+        assert_eq!(serde_json::to_string(&Version::V2_0).unwrap(), r#""2.0""#);
+        assert_eq!(
+            serde_json::from_str::<Version>(r#""1.0""#).unwrap(),
+            Version::V1_0
+        );
+        assert!(serde_json::from_str::<Version>(r#""two""#).is_err());
+    }
+}
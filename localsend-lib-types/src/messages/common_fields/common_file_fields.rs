@@ -24,11 +24,100 @@ pub struct FilePreview(String);
 
 /// File (extra) metadata
 ///
-/// Optional unstructured file metadata like accessed/modified time etc
+/// Optional file metadata, modified/accessed times as RFC 3339 timestamps.
+/// ```json
+/// {
+///     "modified": "2021-01-01T12:34:56Z", // nullable
+///     "accessed": "2021-01-01T12:34:56Z" // nullable
+/// }
+/// ```
+/// Timestamps are kept as sent, so a malformed one doesn't spoil the whole request.
+/// Typed access to them needs the `time` feature.
+/// Keys we don't know of are kept too, and sent on as they were.
 #[serde_as]
 #[skip_serializing_none]
-#[derive(Debug, Serialize, Deserialize, Constructor, From, PartialEq, Clone)]
-pub struct FileMeta(HashMap<String, String>);
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct FileMeta {
+    modified: Option<String>,
+    accessed: Option<String>,
+    #[serde(flatten)]
+    other: HashMap<String, String>,
+}
+
+impl FileMeta {
+    /// Metadata from raw key values, as found on the wire
+    pub fn new(mut metadata: HashMap<String, String>) -> Self {
+        FileMeta {
+            modified: metadata.remove("modified"),
+            accessed: metadata.remove("accessed"),
+            other: metadata,
+        }
+    }
+
+    /// Modified timestamp, as sent
+    pub fn modified(&self) -> Option<&str> {
+        self.modified.as_deref()
+    }
+
+    /// Accessed timestamp, as sent
+    pub fn accessed(&self) -> Option<&str> {
+        self.accessed.as_deref()
+    }
+
+    /// Value of some other (unknown to us) key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        match key {
+            "modified" => self.modified(),
+            "accessed" => self.accessed(),
+            key => self.other.get(key).map(String::as_str),
+        }
+    }
+}
+
+impl From<HashMap<String, String>> for FileMeta {
+    fn from(metadata: HashMap<String, String>) -> Self {
+        FileMeta::new(metadata)
+    }
+}
+
+#[cfg(feature = "time")]
+impl FileMeta {
+    /// Metadata with given times, ex. from filesystem metadata
+    pub fn from_times(
+        modified: Option<time::OffsetDateTime>,
+        accessed: Option<time::OffsetDateTime>,
+    ) -> Self {
+        FileMeta {
+            modified: modified.and_then(format_timestamp),
+            accessed: accessed.and_then(format_timestamp),
+            other: HashMap::new(),
+        }
+    }
+
+    /// Modified time, if sent and valid
+    pub fn modified_time(&self) -> Option<time::OffsetDateTime> {
+        self.modified().and_then(parse_timestamp)
+    }
+
+    /// Accessed time, if sent and valid
+    pub fn accessed_time(&self) -> Option<time::OffsetDateTime> {
+        self.accessed().and_then(parse_timestamp)
+    }
+}
+
+#[cfg(feature = "time")]
+fn parse_timestamp(timestamp: &str) -> Option<time::OffsetDateTime> {
+    time::OffsetDateTime::parse(timestamp, &time::format_description::well_known::Rfc3339).ok()
+}
+
+/// In UTC, as the official app does. Fails for years outside 0..=9999.
+#[cfg(feature = "time")]
+fn format_timestamp(datetime: time::OffsetDateTime) -> Option<String> {
+    datetime
+        .to_offset(time::UtcOffset::UTC)
+        .format(&time::format_description::well_known::Rfc3339)
+        .ok()
+}
 
 /// File Info
 ///
@@ -70,13 +159,28 @@ pub struct FileInfo {
 ///     }
 ///   }
 /// ```
-#[derive(Debug, Serialize, Deserialize, Constructor, From, PartialEq, AsRef)]
+#[derive(Debug, Serialize, Deserialize, Constructor, From, PartialEq, AsRef, Clone)]
 pub struct FilesInfoMap(HashMap<FileId, FileInfo>);
 
+/// Files keyed by their id
+impl FromIterator<FileInfo> for FilesInfoMap {
+    fn from_iter<I: IntoIterator<Item = FileInfo>>(files: I) -> Self {
+        FilesInfoMap(
+            files
+                .into_iter()
+                .map(|file_info| (file_info.id.clone(), file_info))
+                .collect(),
+        )
+    }
+}
+
 /// File upload token
 ///
 /// Token required to upload each file during send using upload api
-#[derive(Debug, Serialize, Deserialize, Constructor, From, PartialEq)]
+#[derive(
+    Debug, Display, Serialize, Deserialize, Constructor, From, PartialEq, Eq, Clone, AsRef,
+)]
+#[from(forward)]
 pub struct FileUploadToken(String);
 
 /// File Token Map
@@ -87,5 +191,64 @@ pub struct FileUploadToken(String);
 ///     "someOtherFileId": "someOtherFileToken"
 ///  }
 /// ```
-#[derive(Debug, Serialize, Deserialize, Constructor, From, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Constructor, From, PartialEq, AsRef)]
 pub struct FilesTokenMap(HashMap<FileId, FileUploadToken>);
+
+impl FilesTokenMap {
+    /// Token to upload file with, if it was accepted
+    pub fn token(&self, file_id: &FileId) -> Option<&FileUploadToken> {
+        self.0.get(file_id)
+    }
+
+    pub fn contains(&self, file_id: &FileId) -> bool {
+        self.0.contains_key(file_id)
+    }
+
+    /// Files accepted, with their tokens
+    pub fn iter(&self) -> impl Iterator<Item = (&FileId, &FileUploadToken)> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromIterator<(FileId, FileUploadToken)> for FilesTokenMap {
+    fn from_iter<I: IntoIterator<Item = (FileId, FileUploadToken)>>(tokens: I) -> Self {
+        FilesTokenMap(tokens.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::FileMeta;
+
+    #[test]
+    fn filemeta_keeps_unknown_keys() {
+        let metadata_json = json!({
+            "modified": "2021-01-01T12:34:56Z",
+            "accessed": "not a time",
+            "owner": "nain",
+        });
+        let metadata: FileMeta = serde_json::from_value(metadata_json.clone()).unwrap();
+        assert_eq!(metadata.modified(), Some("2021-01-01T12:34:56Z"));
+        assert_eq!(metadata.get("owner"), Some("nain"));
+        assert_eq!(serde_json::to_value(&metadata).unwrap(), metadata_json);
+        #[cfg(feature = "time")]
+        {
+            let modified = metadata.modified_time().unwrap();
+            assert_eq!(modified.unix_timestamp(), 1609504496);
+            assert_eq!(metadata.accessed_time(), None);
+            let written = FileMeta::from_times(Some(modified), None);
+            assert_eq!(written.modified(), metadata.modified());
+            assert_eq!(written.accessed(), None);
+        }
+    }
+}
@@ -208,7 +208,7 @@ mod tests {
             Fingerprint::new("random string".into()),
         );
         let constructed_multicast_announce = MulticastAnnounce::new(
-            Version::new("2.0".into()),
+            Version::V2_0,
             MulticastCommon::new(device_info, Port::new(53317), Protocol::Https, Some(true)),
             serde_bool::True,
         );
@@ -250,7 +250,7 @@ mod tests {
             }
         );
         let constructed_response_1 = MulticastResponse::new(
-            "2.0".into(),
+            Version::V2_0,
             MulticastCommon::new(
                 DeviceInfo::new(
                     "Secret Banana".into(),
@@ -265,7 +265,7 @@ mod tests {
             None,
         );
         let constructed_response_2 = MulticastResponse::new(
-            "2.0".into(),
+            Version::V2_0,
             MulticastCommon::new(
                 DeviceInfo::new(
                     "Secret Banana".into(),
@@ -308,7 +308,7 @@ mod tests {
             }
         );
         let constructed_request = LegacyRegister::new(
-            Version::new("2.0".into()),
+            Version::V2_0,
             DeviceInfo::new(
                 Alias::new("Secret Banana".into()),
                 Some(DeviceModel::new("Windows".into())),
@@ -341,7 +341,7 @@ mod tests {
             }
         );
         let constructed_response = LegacyRegisterResponse::new(
-            Version::new("2.0".into()),
+            Version::V2_0,
             DeviceInfo::new(
                 Alias::new("Nice Orange".into()),
                 Some("Samsung".into()),
@@ -79,7 +79,7 @@ pub struct PrepareDownloadResponse {
 }
 
 #[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters)]
-struct PrepareDownloadMeta {
+pub struct PrepareDownloadMeta {
     version: Version,
     #[serde(flatten)]
     device_info: DeviceInfo,
@@ -92,7 +92,9 @@ mod tests {
 
     use serde_json::json;
 
-    use crate::messages::common_fields::{DeviceInfo, DeviceType, FileId, FileInfo, FilesInfoMap};
+    use crate::messages::common_fields::{
+        DeviceInfo, DeviceType, FileId, FileInfo, FilesInfoMap, Version,
+    };
 
     use super::{PrepareDownloadMeta, PrepareDownloadResponse};
 
@@ -130,7 +132,7 @@ mod tests {
             }
         );
         let info = PrepareDownloadMeta::new(
-            "2.0".to_string().into(),
+            Version::V2_0,
             DeviceInfo::new(
                 "Nice Orange".to_string().into(),
                 Some("Samsung".to_string().into()),
@@ -1,9 +1,11 @@
+use derive_getters::{Dissolve, Getters};
 use derive_more::derive::Constructor;
 use serde::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
 
 use super::common_fields::{
-    DeviceInfo, FilesInfoMap, FilesTokenMap, Port, Protocol, SessionId, Version,
+    DeviceInfo, FileId, FileInfo, FileUploadToken, FilesInfoMap, FilesTokenMap, Port, Protocol,
+    SessionId, Version,
 };
 
 /// Upload request (Metadata Only)
@@ -26,8 +28,8 @@ use super::common_fields::{
 ///     "deviceModel": "Samsung", // nullable
 ///     "deviceType": "mobile", // mobile | desktop | web | headless | server, nullable
 ///     "fingerprint": "random string", // ignored in HTTPS mode
-///     "port": 53317,
-///     "protocol": "https", // http | https
+///     "port": 53317, // nullable, if sender doesn't listen
+///     "protocol": "https", // http | https, nullable
 ///     "download": true, // if the download API (5.2 and 5.3) is active (optional, default: false)
 ///   },
 ///   "files": {
@@ -54,24 +56,77 @@ use super::common_fields::{
 ///   }
 /// }
 /// ```
-#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters)]
 #[serde(rename_all = "camelCase")]
-struct PrepareUploadRequest {
+pub struct PrepareUploadRequest {
     info: PrepareUploadDeviceInfo,
     files: FilesInfoMap,
 }
 
+impl PrepareUploadRequest {
+    /// Request offering files, from sender described by info
+    pub fn from_files(
+        info: impl Into<PrepareUploadDeviceInfo>,
+        files: impl IntoIterator<Item = FileInfo>,
+    ) -> Self {
+        PrepareUploadRequest {
+            info: info.into(),
+            files: files.into_iter().collect(),
+        }
+    }
+}
+
 #[skip_serializing_none]
-#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq)]
-struct PrepareUploadDeviceInfo {
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters)]
+pub struct PrepareUploadDeviceInfo {
     version: Version,
     #[serde(flatten)]
     device_info: DeviceInfo,
-    port: Port,
-    protocol: Protocol,
+    /// Where the sender can be reached, if it listens at all
+    port: Option<Port>,
+    protocol: Option<Protocol>,
     download: Option<bool>,
 }
 
+/// Current protocol version, not listening (no port nor protocol), and no download api
+impl From<DeviceInfo> for PrepareUploadDeviceInfo {
+    fn from(device_info: DeviceInfo) -> Self {
+        PrepareUploadDeviceInfo {
+            version: Version::default(),
+            device_info,
+            port: None,
+            protocol: None,
+            download: None,
+        }
+    }
+}
+
+impl PrepareUploadDeviceInfo {
+    /// Port the sender can be reached at
+    pub fn with_port(self, port: impl Into<Port>) -> Self {
+        PrepareUploadDeviceInfo {
+            port: Some(port.into()),
+            ..self
+        }
+    }
+
+    /// Protocol the sender can be reached over
+    pub fn with_protocol(self, protocol: Protocol) -> Self {
+        PrepareUploadDeviceInfo {
+            protocol: Some(protocol),
+            ..self
+        }
+    }
+
+    /// Whether the sender serves the download api
+    pub fn with_download(self, download: bool) -> Self {
+        PrepareUploadDeviceInfo {
+            download: Some(download),
+            ..self
+        }
+    }
+}
+
 /// Response
 ///
 /// ```json
@@ -83,13 +138,20 @@ struct PrepareUploadDeviceInfo {
 ///   }
 /// }
 /// ```
-#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters, Dissolve)]
 #[serde(rename_all = "camelCase")]
-struct PrepareUploadResponse {
+pub struct PrepareUploadResponse {
     session_id: SessionId,
     files: FilesTokenMap,
 }
 
+impl PrepareUploadResponse {
+    /// Token to upload file with, if receiver accepted it
+    pub fn token(&self, file_id: &FileId) -> Option<&FileUploadToken> {
+        self.files.token(file_id)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
@@ -99,7 +161,7 @@ mod tests {
     use crate::messages::{
         common_fields::{
             DeviceInfo, DeviceType, FileId, FileInfo, FileMeta, FilesInfoMap, FilesTokenMap,
-            Protocol,
+            Protocol, Version,
         },
         upload::{PrepareUploadDeviceInfo, PrepareUploadRequest},
     };
@@ -146,15 +208,15 @@ mod tests {
         );
 
         let info = PrepareUploadDeviceInfo::new(
-            "2.0".to_string().into(),
+            Version::V2_0,
             DeviceInfo::new(
                 "Nice Orange".to_string().into(),
                 Some("Samsung".to_string().into()),
                 DeviceType::Mobile,
                 "random string".to_string().into(),
             ),
-            53317.into(),
-            Protocol::Https,
+            Some(53317.into()),
+            Some(Protocol::Https),
             Some(true),
         );
         let mut files_map: HashMap<FileId, FileInfo> = HashMap::new();
@@ -227,4 +289,54 @@ mod tests {
         let written_response = serde_json::to_value(constructed_response).unwrap();
         assert_eq!(response_json, written_response);
     }
+
+    #[test]
+    fn prepareupload_build_and_look_up() {
+        let device_info = DeviceInfo::new(
+            "Nice Orange".to_string().into(),
+            None,
+            DeviceType::Headless,
+            "random string".to_string().into(),
+        );
+        let file = |id: &str| {
+            FileInfo::new(
+                id.to_string().into(),
+                format!("{id}.txt"),
+                4,
+                "text/plain".to_string(),
+                None,
+                None,
+                None,
+            )
+        };
+        let request = PrepareUploadRequest::from_files(
+            PrepareUploadDeviceInfo::from(device_info.clone())
+                .with_port(53318)
+                .with_protocol(Protocol::Http),
+            [file("a"), file("b")],
+        );
+        let request_json = serde_json::to_value(&request).unwrap();
+        assert_eq!(request_json["info"]["port"], 53318);
+        assert_eq!(request_json["info"]["protocol"], "http");
+        assert_eq!(request_json["info"]["alias"], "Nice Orange");
+        assert!(request_json["info"].get("download").is_none());
+        // Senders not listening leave port and protocol out
+        let not_listening = PrepareUploadRequest::from_files(device_info.clone(), []);
+        let not_listening_json = serde_json::to_value(&not_listening).unwrap();
+        assert!(not_listening_json["info"].get("port").is_none());
+        assert!(not_listening_json["info"].get("protocol").is_none());
+        assert_eq!(request_json["files"]["b"]["fileName"], "b.txt");
+
+        let (a, b): (FileId, FileId) = ("a".to_string().into(), "b".to_string().into());
+        let response = PrepareUploadResponse::new(
+            "mySessionId".to_string().into(),
+            [(a.clone(), "token a".to_string().into())]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(response.token(&a), Some(&"token a".to_string().into()));
+        assert_eq!(response.token(&b), None);
+        assert!(response.files().contains(&a));
+        assert_eq!(response.files().len(), 1);
+    }
 }
@@ -0,0 +1,483 @@
+//! Protocol v1, still spoken by older LocalSend installs
+//!
+//! Routes live under `/api/localsend/v1/`, and uploads go by other names:
+//! `send-request` (v2 prepare-upload) and `send` (v2 upload).
+//! Messages carry no version, port or protocol, and uploads have no sessions.
+//! There is no download api.
+//!
+//! Conversions to and from v2 messages fill in what v1 leaves out:
+//! v1 peers listen on the default port, over https.
+
+use std::collections::HashMap;
+
+use derive_getters::Getters;
+use derive_more::derive::{Constructor, From};
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+
+use super::{
+    common_fields::{
+        Alias, DeviceInfo, DeviceModel, DeviceType, FileId, FileInfo as FileInfoV2, FilePreview,
+        FileUploadToken, FilesTokenMap, Fingerprint, Port, Protocol, SessionId, Version,
+    },
+    discover::{LegacyRegisterResponse, MulticastCommon},
+    upload::{PrepareUploadRequest, PrepareUploadResponse},
+};
+
+/// Info
+///
+/// Sender of a send request, as described in it.
+///
+/// ```json
+/// {
+///   "alias": "Nice Orange",
+///   "deviceModel": "Samsung", // nullable
+///   "deviceType": "mobile" // mobile | desktop | web
+/// }
+/// ```
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Info {
+    alias: Alias,
+    device_model: Option<DeviceModel>,
+    device_type: DeviceType,
+}
+
+/// v1 only knows mobile, desktop and web devices
+impl From<DeviceInfo> for Info {
+    fn from(device_info: DeviceInfo) -> Self {
+        let device_type = match device_info.device_type() {
+            DeviceType::Headless | DeviceType::Server => DeviceType::Desktop,
+            device_type => device_type.clone(),
+        };
+        Info {
+            alias: device_info.alias().clone(),
+            device_model: device_info.device_model().clone(),
+            device_type,
+        }
+    }
+}
+
+/// Register (HTTP discovery)
+///
+/// `POST /api/localsend/v1/register`
+///
+/// Request, and response alike
+///
+/// ```json
+/// {
+///   "alias": "Secret Banana",
+///   "deviceModel": "Windows",
+///   "deviceType": "desktop",
+///   "fingerprint": "random string"
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters, Clone)]
+pub struct Register {
+    #[serde(flatten)]
+    info: Info,
+    fingerprint: Fingerprint,
+}
+
+impl From<DeviceInfo> for Register {
+    fn from(device_info: DeviceInfo) -> Self {
+        Register {
+            fingerprint: device_info.fingerprint().clone(),
+            info: device_info.into(),
+        }
+    }
+}
+
+impl From<Register> for DeviceInfo {
+    fn from(register: Register) -> Self {
+        DeviceInfo::new(
+            register.info.alias,
+            register.info.device_model,
+            register.info.device_type,
+            register.fingerprint,
+        )
+    }
+}
+
+impl From<Register> for LegacyRegisterResponse {
+    fn from(register: Register) -> Self {
+        LegacyRegisterResponse::new(Version::V1_0, register.into(), None)
+    }
+}
+
+/// Multicast UDP announcement, or response to one
+///
+/// ```json
+/// {
+///   "alias": "Nice Orange",
+///   "deviceModel": "Samsung", // nullable
+///   "deviceType": "mobile", // mobile | desktop | web
+///   "fingerprint": "random string",
+///   "announcement": true
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters, Clone)]
+pub struct MulticastMessage {
+    #[serde(flatten)]
+    register: Register,
+    announcement: bool,
+}
+
+impl From<MulticastMessage> for MulticastCommon {
+    fn from(message: MulticastMessage) -> Self {
+        MulticastCommon::new(
+            message.register.into(),
+            Port::default(),
+            Protocol::Https,
+            None,
+        )
+    }
+}
+
+/// Kind of file, v1 has no mime types
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FileType {
+    Image,
+    Video,
+    Pdf,
+    Text,
+    Other,
+}
+
+impl FileType {
+    /// Kind of file of given mime type
+    pub fn from_mime(mime: &str) -> Self {
+        let (top_level, _subtype) = mime.split_once('/').unwrap_or((mime, ""));
+        match top_level {
+            _ if mime.eq_ignore_ascii_case("application/pdf") => FileType::Pdf,
+            "image" => FileType::Image,
+            "video" => FileType::Video,
+            "text" => FileType::Text,
+            _ => FileType::Other,
+        }
+    }
+
+    /// Mime type for the kind, as close as we can tell
+    pub fn mime(&self) -> &'static str {
+        match self {
+            FileType::Image => "image/*",
+            FileType::Video => "video/*",
+            FileType::Pdf => "application/pdf",
+            FileType::Text => "text/plain",
+            FileType::Other => "application/octet-stream",
+        }
+    }
+}
+
+/// File info, without hash or metadata
+///
+/// ```json
+/// {
+///   "id": "some file id",
+///   "fileName": "my image.png",
+///   "size": 324242, // bytes
+///   "fileType": "image", // image | video | pdf | text | other
+///   "preview": "*preview data*" // nullable
+/// }
+/// ```
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FileInfo {
+    id: FileId,
+    file_name: String,
+    size: u64,
+    file_type: FileType,
+    preview: Option<FilePreview>,
+}
+
+impl From<FileInfoV2> for FileInfo {
+    fn from(file_info: FileInfoV2) -> Self {
+        FileInfo {
+            id: file_info.id().clone(),
+            file_name: file_info.file_name().clone(),
+            size: file_info.size(),
+            file_type: FileType::from_mime(file_info.file_type()),
+            preview: file_info.preview().clone(),
+        }
+    }
+}
+
+impl From<FileInfo> for FileInfoV2 {
+    fn from(file_info: FileInfo) -> Self {
+        FileInfoV2::new(
+            file_info.id,
+            file_info.file_name,
+            file_info.size,
+            file_info.file_type.mime().to_string(),
+            None,
+            file_info.preview,
+            None,
+        )
+    }
+}
+
+/// Send request (Metadata Only)
+///
+/// `POST /api/localsend/v1/send-request`
+///
+/// ```json
+/// {
+///   "info": {
+///     "alias": "Nice Orange",
+///     "deviceModel": "Samsung", // nullable
+///     "deviceType": "mobile" // mobile | desktop | web
+///   },
+///   "files": {
+///     "some file id": {
+///       "id": "some file id",
+///       "fileName": "my image.png",
+///       "size": 324242, // bytes
+///       "fileType": "image", // image | video | pdf | text | other
+///       "preview": "*preview data*" // nullable
+///     }
+///   }
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters)]
+pub struct SendRequest {
+    info: Info,
+    files: HashMap<FileId, FileInfo>,
+}
+
+impl From<&PrepareUploadRequest> for SendRequest {
+    fn from(request: &PrepareUploadRequest) -> Self {
+        SendRequest {
+            info: request.info().device_info().clone().into(),
+            files: request
+                .files()
+                .as_ref()
+                .iter()
+                .map(|(file_id, file_info)| (file_id.clone(), file_info.clone().into()))
+                .collect(),
+        }
+    }
+}
+
+/// Response, tokens of the files accepted
+///
+/// ```json
+/// {
+///   "some file id": "some token",
+///   "another file id": "some other token"
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize, Constructor, From, PartialEq)]
+#[serde(transparent)]
+pub struct SendResponse(FilesTokenMap);
+
+/// Without sessions in v1, the session id is left empty
+impl From<SendResponse> for PrepareUploadResponse {
+    fn from(response: SendResponse) -> Self {
+        PrepareUploadResponse::new(SessionId::new(String::new()), response.0)
+    }
+}
+
+impl From<PrepareUploadResponse> for SendResponse {
+    fn from(response: PrepareUploadResponse) -> Self {
+        let (_session_id, files) = response.dissolve();
+        SendResponse(files)
+    }
+}
+
+/// Send file
+///
+/// `POST /api/localsend/v1/send?fileId=some file id&token=some token`
+///
+/// Request body is the file (binary)
+#[derive(Debug, Serialize, Deserialize, Constructor, PartialEq, Getters)]
+#[serde(rename_all = "camelCase")]
+pub struct SendParams {
+    file_id: FileId,
+    token: FileUploadToken,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::{FileInfo, FileType, Info, MulticastMessage, Register, SendRequest, SendResponse};
+    use crate::messages::{
+        common_fields::{
+            DeviceInfo, DeviceType, FileId, FilesInfoMap, FilesTokenMap, Protocol, Version,
+        },
+        discover::MulticastCommon,
+        upload::{PrepareUploadDeviceInfo, PrepareUploadRequest, PrepareUploadResponse},
+    };
+
+    #[test]
+    fn multicast_deserialize_serialize() {
+        let message_json = json!(
+            {
+              "alias": "Nice Orange",
+              "deviceModel": "Samsung", // nullable
+              "deviceType": "mobile", // mobile | desktop | web
+              "fingerprint": "random string",
+              "announcement": true
+            }
+        );
+        let constructed_message = MulticastMessage::new(
+            Register::new(
+                Info::new(
+                    "Nice Orange".into(),
+                    Some("Samsung".into()),
+                    DeviceType::Mobile,
+                ),
+                "random string".into(),
+            ),
+            true,
+        );
+        let read_message = serde_json::from_value(message_json.clone()).unwrap();
+        assert_eq!(constructed_message, read_message);
+        assert_eq!(
+            serde_json::to_value(&constructed_message).unwrap(),
+            message_json
+        );
+        let multicast_common = MulticastCommon::from(constructed_message);
+        assert_eq!(
+            multicast_common.device_info().alias().to_string(),
+            "Nice Orange"
+        );
+        assert_eq!(*multicast_common.protocol(), Protocol::Https);
+    }
+
+    #[test]
+    fn send_request_response_deserialize_serialize() {
+        let request_json = json!(
+            {
+              "info": {
+                "alias": "Nice Orange",
+                "deviceModel": "Samsung", // nullable
+                "deviceType": "mobile" // mobile | desktop | web
+              },
+              "files": {
+                "some file id": {
+                  "id": "some file id",
+                  "fileName": "my image.png",
+                  "size": 324242, // bytes
+                  "fileType": "image", // image | video | pdf | text | other
+                  "preview": "*preview data*" // nullable
+                },
+                "another file id": {
+                  "id": "another file id",
+                  "fileName": "another image.jpg",
+                  "size": 1234,
+                  "fileType": "image",
+                  "preview": "*preview data*"
+                }
+              }
+            }
+        );
+        let response_json = json!(
+            {
+              "some file id": "some token",
+              "another file id": "some other token"
+            }
+        );
+        let files = [
+            ("some file id", "my image.png", 324242),
+            ("another file id", "another image.jpg", 1234),
+        ]
+        .map(|(id, file_name, size)| {
+            FileInfo::new(
+                id.to_string().into(),
+                file_name.to_string(),
+                size,
+                FileType::Image,
+                Some("*preview data*".to_string().into()),
+            )
+        });
+        let constructed_request = SendRequest::new(
+            Info::new(
+                "Nice Orange".into(),
+                Some("Samsung".into()),
+                DeviceType::Mobile,
+            ),
+            files
+                .iter()
+                .map(|file_info| (file_info.id().clone(), file_info.clone()))
+                .collect(),
+        );
+        let read_request: SendRequest = serde_json::from_value(request_json.clone()).unwrap();
+        assert_eq!(constructed_request, read_request);
+        assert_eq!(
+            serde_json::to_value(&constructed_request).unwrap(),
+            request_json
+        );
+
+        // Same request, from v2
+        let v2_request = PrepareUploadRequest::new(
+            PrepareUploadDeviceInfo::from(DeviceInfo::new(
+                "Nice Orange".into(),
+                Some("Samsung".into()),
+                DeviceType::Mobile,
+                "random string".into(),
+            )),
+            files
+                .into_iter()
+                .map(|file_info| {
+                    let file_info: crate::messages::common_fields::FileInfo = file_info.into();
+                    assert_eq!(file_info.file_type(), "image/*");
+                    file_info
+                })
+                .collect::<FilesInfoMap>(),
+        );
+        assert_eq!(SendRequest::from(&v2_request), read_request);
+
+        let mut tokens = HashMap::new();
+        tokens.insert(FileId::new("some file id".into()), "some token".into());
+        tokens.insert(
+            FileId::new("another file id".into()),
+            "some other token".into(),
+        );
+        let constructed_response = SendResponse::new(FilesTokenMap::new(tokens));
+        let read_response = serde_json::from_value(response_json.clone()).unwrap();
+        assert_eq!(constructed_response, read_response);
+        assert_eq!(
+            serde_json::to_value(&constructed_response).unwrap(),
+            response_json
+        );
+        let v2_response = PrepareUploadResponse::from(read_response);
+        assert_eq!(v2_response.session_id().to_string(), "");
+        assert!(v2_response
+            .token(&"some file id".to_string().into())
+            .is_some());
+        assert_eq!(SendResponse::from(v2_response), constructed_response);
+    }
+
+    #[test]
+    fn v1_device_types_and_file_types() {
+        let headless = DeviceInfo::new(
+            "Nice Orange".into(),
+            None,
+            DeviceType::Headless,
+            "random string".into(),
+        );
+        assert_eq!(
+            *Info::from(headless.clone()).device_type(),
+            DeviceType::Desktop
+        );
+        let register = Register::from(headless);
+        assert_eq!(
+            serde_json::to_value(&register).unwrap(),
+            json!({"alias": "Nice Orange", "deviceType": "desktop", "fingerprint": "random string"})
+        );
+        assert_eq!(
+            *crate::messages::discover::LegacyRegisterResponse::from(register).version(),
+            Version::V1_0
+        );
+        assert_eq!(FileType::from_mime("image/png"), FileType::Image);
+        assert_eq!(FileType::from_mime("application/pdf"), FileType::Pdf);
+        assert_eq!(FileType::from_mime("text/x-rust"), FileType::Text);
+        assert_eq!(FileType::from_mime("application/zip"), FileType::Other);
+    }
+}